@@ -1,26 +1,90 @@
 #[derive(Debug)]
 pub struct Task<'a> {
-    pub name: &'a str,
+    /// Targets of the rule, each getting the same prerequisites and recipe.
+    pub names: Vec<&'a str>,
+    /// Set for `target:: deps` rules.
+    pub double_colon: bool,
+    /// The `%.o` in a static pattern rule `$(OBJS): %.o: %.c`, whose
+    /// dependencies are then prerequisite patterns.
+    pub target_pattern: Option<&'a str>,
     pub dependencies: Vec<&'a str>,
-    pub commands: Vec<&'a str>,
+    /// Prerequisites after `|`, which are only built before the target, not
+    /// checked for being newer.
+    pub order_only: Vec<&'a str>,
+    pub commands: Vec<Recipe<'a>>,
+    /// Target-specific variables, e.g. `debug: CFLAGS += -g`. Such a line
+    /// has no prerequisites or recipe.
+    pub local_vars: Vec<Variable<'a>>,
+    /// Line the rule starts on, counting from 1. Zero when unknown.
+    pub line: usize,
 }
 
 #[derive(Debug)]
 pub struct Variable<'a> {
+    /// Leading `export`, `unexport` or `override` keywords.
+    pub modifiers: Vec<&'a str>,
     pub name: &'a str,
     pub op: &'a str,
     pub value: &'a str,
 }
 
+/// A line of a rule's recipe.
+#[derive(Debug, PartialEq)]
+pub enum Recipe<'a> {
+    Command(&'a str),
+    /// Conditional around part of the recipe.
+    Conditional(Conditional<'a, Recipe<'a>>),
+}
+
+/// Conditional around whole terms, or around recipe lines when `T` is
+/// [`Recipe`].
+#[derive(Debug, PartialEq)]
+pub struct Conditional<'a, T = Term<'a>> {
+    /// One of `ifeq`, `ifneq`, `ifdef` or `ifndef`.
+    pub kind: &'a str,
+    /// The two compared values, or the variable name for `ifdef`/`ifndef`.
+    pub operands: Vec<&'a str>,
+    pub then: Vec<T>,
+    /// What comes after `else`. An `else ifeq ...` chain is a single nested
+    /// conditional.
+    pub otherwise: Vec<T>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Include<'a> {
+    /// Paths of the included makefiles, possibly with variable references.
+    pub files: Vec<&'a str>,
+    /// Set for `-include` and `sinclude`, which ignore missing files.
+    pub optional: bool,
+}
+
+/// `export` or `unexport` without an assignment.
+#[derive(Debug, PartialEq)]
+pub struct Export<'a> {
+    /// Variables to (un)export. Empty for all of them.
+    pub names: Vec<&'a str>,
+    /// Unset for `unexport`.
+    pub export: bool,
+}
+
 #[derive(Debug)]
 pub enum Term<'a> {
     Task(Task<'a>),
     Variable(Variable<'a>),
+    Conditional(Conditional<'a>),
+    Include(Include<'a>),
+    Export(Export<'a>),
+    /// A `define` block. The body keeps its line breaks.
+    Define {
+        name: &'a str,
+        op: &'a str,
+        body: &'a str,
+    },
     Empty,
     Unimplemented(&'static str),
 }
 
 pub trait Parse<'a> {
     type Error;
-    fn parse(input: &'a str) -> Result<Vec<Term>, Self::Error>;
+    fn parse(input: &'a str) -> Result<Vec<Term<'a>>, Self::Error>;
 }
@@ -0,0 +1,516 @@
+use crate::makefile::Makefile;
+
+/// Expands variable references and function calls against a makefile's
+/// variables, collecting warnings about malformed input on the way.
+pub struct Expander<'a> {
+    makefile: &'a Makefile,
+    /// Variables bound by functions like `foreach`, innermost last.
+    locals: Vec<(String, String)>,
+    /// Variables whose value is being expanded, to catch self references.
+    expanding: Vec<String>,
+    pub warnings: Vec<String>,
+    /// References the makefile alone doesn't determine: undefined variables,
+    /// ones assigned from a shell command, and calls to functions that
+    /// aren't supported.
+    pub unresolved: Vec<String>,
+}
+
+impl<'a> Expander<'a> {
+    pub fn new(makefile: &'a Makefile) -> Self {
+        Self {
+            makefile,
+            locals: Vec::new(),
+            expanding: Vec::new(),
+            warnings: Vec::new(),
+            unresolved: Vec::new(),
+        }
+    }
+
+    /// Binds `name` to `value` ahead of the makefile's variables.
+    pub fn bind(&mut self, name: &str, value: &str) {
+        self.locals.push((name.to_string(), value.to_string()));
+    }
+
+    pub fn expand(&mut self, input: &str) -> String {
+        let mut out = String::new();
+        let mut rest = input;
+        while let Some(start) = rest.find('$') {
+            out.push_str(&rest[..start]);
+            rest = &rest[start..];
+            match (rest.as_bytes().get(1), reference(rest)) {
+                (Some(b'$'), _) => {
+                    out.push('$');
+                    rest = &rest[2..];
+                }
+                (_, Some((inner, len))) => {
+                    out.push_str(&self.expand_reference(inner));
+                    rest = &rest[len..];
+                }
+                (Some(b'(' | b'{'), None) => {
+                    // Keep the unterminated opener as text, but still expand
+                    // whatever follows it.
+                    self.warnings
+                        .push(format!("Unterminated variable reference in {:?}", input));
+                    out.push('$');
+                    rest = &rest[1..];
+                }
+                _ => {
+                    out.push('$');
+                    rest = &rest[1..];
+                }
+            }
+        }
+        out.push_str(rest);
+        out
+    }
+
+    fn expand_reference(&mut self, reference: &str) -> String {
+        if let Some((function, args)) = reference.split_once([' ', '\t']) {
+            if let Some(out) = self.call(function, args) {
+                return out;
+            }
+        }
+        // Substitution references, e.g. `$(SRCS:.c=.o)`.
+        if let [name, substitution] = split_top_level(reference, b':', 2)[..] {
+            if let Some((pattern, replacement)) = substitution.split_once('=') {
+                let (pattern, replacement) = match pattern.contains('%') {
+                    true => (self.expand(pattern), self.expand(replacement)),
+                    false => (
+                        format!("%{}", self.expand(pattern)),
+                        format!("%{}", self.expand(replacement)),
+                    ),
+                };
+                let text = self.variable(name);
+                return patsubst(&pattern, &replacement, &text);
+            }
+        }
+        self.variable(reference)
+    }
+
+    fn variable(&mut self, name: &str) -> String {
+        // Names can be computed from other variables, e.g. `$(CC_$(ARCH))`.
+        let name = self.expand(name);
+        if let Some((_, value)) = self.locals.iter().rev().find(|(n, _)| *n == name) {
+            return value.clone();
+        }
+        // Undefined variables expand to nothing, same as in make.
+        let Some(value) = self.makefile.variables.get(&name) else {
+            self.unresolved.push(name.clone());
+            return self.makefile.env.get(&name).cloned().unwrap_or_default();
+        };
+        // The value of `!=` is a shell command, not make syntax, and that of
+        // `:=` was expanded when assigned.
+        if self.makefile.shell_assigned.contains(&name) {
+            self.unresolved.push(name);
+            return value.value.clone();
+        }
+        if value.is_immediate() {
+            return value.value.clone();
+        }
+        if self.expanding.contains(&name) {
+            self.warnings
+                .push(format!("Recursive variable {:?} references itself", name));
+            return value.value.clone();
+        }
+        // Values may reference other variables in turn, e.g. `A = $(B)`.
+        self.expanding.push(name);
+        let out = self.expand(&value.value);
+        self.expanding.pop();
+        out
+    }
+
+    /// Splits and expands the `N` comma separated arguments of a function.
+    /// Commas past the `N`th argument belong to the last one.
+    fn args<const N: usize>(&mut self, function: &str, args: &str) -> Option<[String; N]> {
+        let args = split_top_level(args, b',', N);
+        if args.len() < N {
+            self.warnings.push(format!(
+                "Insufficient number of arguments to function {}",
+                function
+            ));
+            return None;
+        }
+        let args = args
+            .into_iter()
+            .map(|a| self.expand(a))
+            .collect::<Vec<String>>();
+        args.try_into().ok()
+    }
+
+    /// Parses the 1-based word index argument of a function.
+    fn index(&mut self, function: &str, n: &str) -> Option<usize> {
+        match n.trim().parse::<usize>() {
+            Ok(n) if n > 0 => Some(n),
+            _ => {
+                self.warnings.push(format!(
+                    "Non-positive index {:?} to function {}",
+                    n.trim(),
+                    function
+                ));
+                None
+            }
+        }
+    }
+
+    fn call(&mut self, function: &str, args: &str) -> Option<String> {
+        let out = match function {
+            "dir" => self
+                .expand(args)
+                .split_whitespace()
+                .map(|w| match w.rfind('/') {
+                    Some(i) => w[..=i].to_string(),
+                    None => "./".to_string(),
+                })
+                .collect::<Vec<String>>()
+                .join(" "),
+            "patsubst" => {
+                let [pattern, replacement, text] = self.args(function, args)?;
+                patsubst(&pattern, &replacement, &text)
+            }
+            "foreach" => {
+                // The text is expanded once per word, so it can't go
+                // through `args` like the other arguments.
+                let [var, list, text] = split_top_level(args, b',', 3)[..] else {
+                    self.warnings.push(format!(
+                        "Insufficient number of arguments to function {}",
+                        function
+                    ));
+                    return None;
+                };
+                let var = self.expand(var);
+                let list = self.expand(list);
+                let mut words = Vec::new();
+                for word in list.split_whitespace() {
+                    self.locals.push((var.clone(), word.to_string()));
+                    words.push(self.expand(text));
+                    self.locals.pop();
+                }
+                words.join(" ")
+            }
+            "subst" => {
+                let [from, to, text] = self.args(function, args)?;
+                match from.is_empty() {
+                    true => text,
+                    false => text.replace(&from, &to),
+                }
+            }
+            "call" => {
+                let args = split_top_level(args, b',', usize::MAX)
+                    .into_iter()
+                    .map(|a| self.expand(a))
+                    .collect::<Vec<String>>();
+                // The body is expanded once the arguments are bound.
+                let body = self
+                    .makefile
+                    .variables
+                    .get(&args[0])
+                    .map(|v| v.value.clone())
+                    .unwrap_or_default();
+                // `$(0)` is the variable's name, `$(1)` the first argument...
+                let depth = self.locals.len();
+                for (i, arg) in args.into_iter().enumerate() {
+                    self.locals.push((i.to_string(), arg));
+                }
+                let out = self.expand(&body);
+                self.locals.truncate(depth);
+                out
+            }
+            "lastword" => self
+                .expand(args)
+                .split_whitespace()
+                .last()
+                .unwrap_or_default()
+                .to_string(),
+            "firstword" => self
+                .expand(args)
+                .split_whitespace()
+                .next()
+                .unwrap_or_default()
+                .to_string(),
+            "words" => self.expand(args).split_whitespace().count().to_string(),
+            "word" => {
+                let [n, text] = self.args(function, args)?;
+                let n = self.index(function, &n)?;
+                text.split_whitespace()
+                    .nth(n - 1)
+                    .unwrap_or_default()
+                    .to_string()
+            }
+            "wordlist" => {
+                let [start, end, text] = self.args(function, args)?;
+                let start = self.index(function, &start)?;
+                let end = end.trim().parse::<usize>().ok()?;
+                text.split_whitespace()
+                    .skip(start - 1)
+                    .take((end + 1).saturating_sub(start))
+                    .collect::<Vec<&str>>()
+                    .join(" ")
+            }
+            _ => return None,
+        };
+        Some(out)
+    }
+}
+
+/// Replaces each word of `text` matching `pattern`. A `%` in the pattern
+/// matches any stem, which then replaces the first `%` in `replacement`.
+fn patsubst(pattern: &str, replacement: &str, text: &str) -> String {
+    text.split_whitespace()
+        .map(|word| match pattern.split_once('%') {
+            Some((prefix, suffix)) => {
+                match word
+                    .strip_prefix(prefix)
+                    .and_then(|w| w.strip_suffix(suffix))
+                {
+                    Some(stem) => replacement.replacen('%', stem, 1),
+                    None => word.to_string(),
+                }
+            }
+            None if word == pattern => replacement.to_string(),
+            None => word.to_string(),
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// Splits `input` on `sep` into at most `max` parts, ignoring separators
+/// inside nested references or parentheses.
+fn split_top_level(input: &str, sep: u8, max: usize) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in input.bytes().enumerate() {
+        match c {
+            b'(' | b'{' => depth += 1,
+            b')' | b'}' => depth -= 1,
+            _ if c == sep && depth == 0 && parts.len() + 1 < max => {
+                parts.push(&input[start..i]);
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    parts.push(&input[start..]);
+    parts
+}
+
+/// Splits the `$(...)` or `${...}` reference at the start of `input` into its
+/// inner text and full length, skipping over nested references.
+fn reference(input: &str) -> Option<(&str, usize)> {
+    let (open, close) = match input.as_bytes().get(1)? {
+        b'(' => (b'(', b')'),
+        b'{' => (b'{', b'}'),
+        _ => return None,
+    };
+    let mut depth = 0;
+    for (i, c) in input.bytes().enumerate().skip(1) {
+        if c == open {
+            depth += 1;
+        } else if c == close {
+            depth -= 1;
+            if depth == 0 {
+                return Some((&input[2..i], i + 1));
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use super::Expander;
+    use crate::makefile::{Makefile, Value};
+
+    fn makefile(variables: &[(&str, &str)]) -> Makefile {
+        Makefile {
+            file: PathBuf::from("Makefile"),
+            variables: variables
+                .iter()
+                .map(|(k, v)| (k.to_string(), Value::new("=", *v)))
+                .collect(),
+            tasks: Default::default(),
+            export_all: false,
+            exported: Default::default(),
+            shell_assigned: Default::default(),
+            includes: Vec::new(),
+            include_cycles: Vec::new(),
+            env: Default::default(),
+            warned: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_references() {
+        let m = makefile(&[
+            ("TOPDIR", "/src"),
+            ("SUFFIX", "_DEBUG"),
+            ("DIR_DEBUG", "debug"),
+            ("NAME", "SUFFIX"),
+        ]);
+        let cases = [
+            ("$(TOPDIR)/lib", "/src/lib"),
+            ("${TOPDIR}/lib", "/src/lib"),
+            ("${TOPDIR}/$(DIR_DEBUG)", "/src/debug"),
+            ("$(DIR$(SUFFIX))", "debug"),
+            ("${DIR${SUFFIX}}", "debug"),
+            ("$(DIR${$(NAME)})/x", "debug/x"),
+            ("$(UNKNOWN)x", "x"),
+        ];
+
+        for (i, (input, expected)) in cases.into_iter().enumerate() {
+            let result = Expander::new(&m).expand(input);
+            assert_eq!(result, expected, "case {:02}, input: {:?}", i, input);
+        }
+    }
+
+    #[test]
+    fn test_recursive() {
+        let m = makefile(&[
+            ("A", "$(B)"),
+            ("B", "${C}"),
+            ("C", "done"),
+            ("SELF", "x $(SELF)"),
+            ("PING", "$(PONG)"),
+            ("PONG", "$(PING)"),
+            ("TWICE", "$(C) $(C)"),
+        ]);
+        let cases = [
+            ("${A}", "done", vec![]),
+            ("$(TWICE)", "done done", vec![]),
+            (
+                "$(SELF)",
+                "x x $(SELF)",
+                vec!["Recursive variable \"SELF\" references itself"],
+            ),
+            (
+                "$(PING)",
+                "$(PONG)",
+                vec!["Recursive variable \"PING\" references itself"],
+            ),
+        ];
+
+        for (i, (input, expected, warnings)) in cases.into_iter().enumerate() {
+            let mut expander = Expander::new(&m);
+            assert_eq!(expander.expand(input), expected, "case {:02}", i);
+            assert_eq!(expander.warnings, warnings, "case {:02}", i);
+        }
+    }
+
+    #[test]
+    fn test_unbalanced() {
+        let m = makefile(&[("X", "${FOO"), ("Y", "$(BAR"), ("FOO", "foo")]);
+        let cases = [
+            (m.variables["X"].value.as_str(), "${FOO"),
+            (m.variables["Y"].value.as_str(), "$(BAR"),
+            ("${FOO $(FOO)", "${FOO foo"),
+        ];
+
+        for (i, (input, expected)) in cases.into_iter().enumerate() {
+            let mut expander = Expander::new(&m);
+            assert_eq!(expander.expand(input), expected, "case {:02}", i);
+            assert_eq!(
+                expander.warnings,
+                vec![format!("Unterminated variable reference in {:?}", input)],
+                "case {:02}",
+                i
+            );
+        }
+    }
+
+    #[test]
+    fn test_patsubst() {
+        let m = makefile(&[("SRCS", "a.c b.c"), ("EXT", ".c")]);
+        let cases = [
+            ("$(patsubst %.c,%.o,$(SRCS))", "a.o b.o"),
+            ("$(patsubst %.c,obj/%.o,a.c b.h)", "obj/a.o b.h"),
+            ("$(patsubst a.c,x,a.c b.c)", "x b.c"),
+            ("$(patsubst %,%-x,a,b)", "a,b-x"),
+            ("$(SRCS:.c=.o)", "a.o b.o"),
+            ("$(SRCS:%.c=obj/%.o)", "obj/a.o obj/b.o"),
+            ("$(SRCS:$(EXT)=.h)", "a.h b.h"),
+        ];
+
+        for (i, (input, expected)) in cases.into_iter().enumerate() {
+            let result = Expander::new(&m).expand(input);
+            assert_eq!(result, expected, "case {:02}, input: {:?}", i, input);
+        }
+    }
+
+    #[test]
+    fn test_foreach() {
+        let m = makefile(&[("DIRS", "a b"), ("d", "outer")]);
+        let cases = [
+            ("$(foreach d,$(DIRS),$(d).o)", "a.o b.o"),
+            (
+                "$(foreach d,$(DIRS),$(d)/Makefile)",
+                "a/Makefile b/Makefile",
+            ),
+            (
+                "$(foreach d,$(DIRS),$(foreach x,1 2,$(d)$(x)))",
+                "a1 a2 b1 b2",
+            ),
+            ("$(foreach d,,$(d))", ""),
+            ("$(foreach d,a,$(d)) $(d)", "a outer"),
+        ];
+
+        for (i, (input, expected)) in cases.into_iter().enumerate() {
+            let result = Expander::new(&m).expand(input);
+            assert_eq!(result, expected, "case {:02}, input: {:?}", i, input);
+        }
+    }
+
+    #[test]
+    fn test_nested_args() {
+        let m = makefile(&[
+            ("TEXT", "a b c"),
+            ("space", " "),
+            ("pair", "$(1)-$(2)"),
+            ("name", "$(0)"),
+        ]);
+        let cases = [
+            ("$(subst $(space),_,$(TEXT))", "a_b_c"),
+            ("$(subst a,b,$(call pair,x,y))", "x-y"),
+            ("$(subst x,z,$(call pair,x,y))", "z-y"),
+            ("$(subst -,$(call pair,1,2),a-b)", "a1-2b"),
+            ("$(call pair,(a,b),c)", "(a,b)-c"),
+            ("$(call name)", "name"),
+            ("$(call pair,a)", "a-"),
+        ];
+
+        for (i, (input, expected)) in cases.into_iter().enumerate() {
+            let result = Expander::new(&m).expand(input);
+            assert_eq!(result, expected, "case {:02}, input: {:?}", i, input);
+        }
+    }
+
+    #[test]
+    fn test_word_functions() {
+        let m = makefile(&[("LIST", "a b c"), ("MAKEFILE_LIST", "Makefile sub/x.mk")]);
+        let cases = [
+            ("$(word 2,a b c)", "b"),
+            ("$(word 2,$(LIST))", "b"),
+            ("$(word 4,$(LIST))", ""),
+            ("$(words a b c)", "3"),
+            ("$(words )", "0"),
+            ("$(firstword $(LIST))", "a"),
+            ("$(lastword $(MAKEFILE_LIST))", "sub/x.mk"),
+            ("$(wordlist 2,3,a b c d)", "b c"),
+            ("$(wordlist 2,9,a b c)", "b c"),
+            ("$(wordlist 3,2,a b c)", ""),
+        ];
+
+        for (i, (input, expected)) in cases.into_iter().enumerate() {
+            let result = Expander::new(&m).expand(input);
+            assert_eq!(result, expected, "case {:02}, input: {:?}", i, input);
+        }
+
+        let mut expander = Expander::new(&m);
+        expander.expand("$(word 0,a b)");
+        assert_eq!(
+            expander.warnings,
+            vec!["Non-positive index \"0\" to function word"]
+        );
+    }
+}
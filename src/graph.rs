@@ -0,0 +1,1204 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    hash::Hash,
+    path::{Path, PathBuf},
+};
+
+use regex::Regex;
+
+use crate::makefile::{External, IDGen, Makefile, Task, ID};
+
+#[derive(Debug)]
+pub struct DependencyGraph {
+    pub makefiles: Vec<Makefile>,
+    /// Files of the makefiles the walk started from.
+    pub roots: Vec<PathBuf>,
+    /// Includes walked as makefiles of their own, as (including, included)
+    /// files.
+    pub includes: Vec<(PathBuf, PathBuf)>,
+    pub externals: HashSet<External<PathBuf>>,
+    /// Externals whose makefile couldn't be found, by their unresolved path.
+    pub unresolved: Vec<(External<String>, crate::Error)>,
+    /// Externals whose directory is a shell variable, by their raw path.
+    pub dynamic: Vec<External<String>>,
+    /// Names of tasks folded away, keyed by the (dependent, dependency) ids
+    /// of the edge that replaced them.
+    pub folded: HashMap<(ID, ID), Vec<String>>,
+}
+
+/// A task as it can be recognized across walks, which assign new ids: the
+/// makefile it's in and its name.
+pub type Node = (PathBuf, String);
+
+/// Changes between two walks of the same makefiles. Every list is sorted.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct GraphDiff {
+    pub added_nodes: Vec<Node>,
+    pub removed_nodes: Vec<Node>,
+    pub added_edges: Vec<(Node, Node)>,
+    pub removed_edges: Vec<(Node, Node)>,
+}
+
+impl GraphDiff {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Walks the makefiles from `root` again, returning the new graph along with
+/// what changed since `previous`.
+pub fn recompute(
+    previous: &DependencyGraph,
+    root: &Path,
+) -> Result<(DependencyGraph, GraphDiff), crate::Error> {
+    let graph = Makefile::walk_from(root)?;
+    let diff = previous.diff(&graph);
+    Ok((graph, diff))
+}
+
+/// Cycles among `ids`, found by a depth first search following `deps`. Each
+/// cycle starts at the task the search reached it from, and is reported once.
+pub(crate) fn find_cycles<'a>(
+    ids: impl IntoIterator<Item = &'a ID>,
+    deps: impl Fn(&ID) -> Vec<&'a ID>,
+) -> Vec<Vec<ID>> {
+    let mut cycles = Vec::new();
+    let mut done = HashSet::new();
+    for start in ids {
+        if done.contains(start) {
+            continue;
+        }
+        // The current path, with the dependencies of each task left to visit.
+        let mut path = vec![(start, deps(start).into_iter())];
+        let mut on_path = HashSet::from([start]);
+        while let Some((id, rest)) = path.last_mut() {
+            let id = *id;
+            match rest.next() {
+                Some(dep) if on_path.contains(dep) => {
+                    let from = path.iter().position(|(id, _)| *id == dep).unwrap_or(0);
+                    cycles.push(path[from..].iter().map(|(id, _)| (*id).clone()).collect());
+                }
+                Some(dep) if !done.contains(dep) => {
+                    on_path.insert(dep);
+                    path.push((dep, deps(dep).into_iter()));
+                }
+                Some(_) => (),
+                None => {
+                    on_path.remove(id);
+                    done.insert(id);
+                    path.pop();
+                }
+            }
+        }
+    }
+    cycles
+}
+
+fn sorted<T: Clone + Ord + Hash>(a: &HashSet<T>, b: &HashSet<T>) -> Vec<T> {
+    let mut v = a.difference(b).cloned().collect::<Vec<T>>();
+    v.sort();
+    v
+}
+
+impl DependencyGraph {
+    /// Nodes and edges in `other` but not in `self`, and the other way
+    /// around.
+    pub fn diff(&self, other: &DependencyGraph) -> GraphDiff {
+        let (old_nodes, old_edges) = self.nodes_and_edges();
+        let (new_nodes, new_edges) = other.nodes_and_edges();
+        GraphDiff {
+            added_nodes: sorted(&new_nodes, &old_nodes),
+            removed_nodes: sorted(&old_nodes, &new_nodes),
+            added_edges: sorted(&new_edges, &old_edges),
+            removed_edges: sorted(&old_edges, &new_edges),
+        }
+    }
+
+    /// Moves every makefile under `from` to the same place under `to`, e.g.
+    /// to diff against a graph walked from another checkout.
+    pub fn relocate(&mut self, from: &Path, to: &Path) {
+        let relocate = |path: &Path| match path.strip_prefix(from) {
+            Ok(rest) => to.join(rest),
+            Err(_) => path.to_path_buf(),
+        };
+        for m in self.makefiles.iter_mut() {
+            m.file = relocate(&m.file);
+            for task in m.tasks.values_mut() {
+                task.origin_file = relocate(&task.origin_file);
+            }
+        }
+        for root in self.roots.iter_mut() {
+            *root = relocate(root);
+        }
+        for (including, included) in self.includes.iter_mut() {
+            *including = relocate(including);
+            *included = relocate(included);
+        }
+        self.externals = self
+            .externals
+            .drain()
+            .map(|mut e| {
+                e.path = relocate(&e.path);
+                e
+            })
+            .collect();
+    }
+
+    /// Every task and edge, by makefile and name rather than by id.
+    fn nodes_and_edges(&self) -> (HashSet<Node>, HashSet<(Node, Node)>) {
+        let node = |id: &ID| self.task(id).map(|(m, t)| (m.file.clone(), t.name.clone()));
+        let mut nodes = HashSet::new();
+        let mut edges = HashSet::new();
+        for m in self.makefiles.iter() {
+            for (id, task) in m.tasks.iter() {
+                let from = (m.file.clone(), task.name.clone());
+                for to in self
+                    .dependencies(id)
+                    .into_iter()
+                    .filter_map(|(_, to)| node(to?))
+                {
+                    edges.insert((from.clone(), to));
+                }
+                nodes.insert(from);
+            }
+        }
+        (nodes, edges)
+    }
+
+    pub fn folded_between(&self, from: &ID, to: &ID) -> Option<&Vec<String>> {
+        self.folded.get(&(from.clone(), to.clone()))
+    }
+
+    /// Folds chains of intermediate tasks, whose names end with one of
+    /// `patterns` (e.g. `.o` or `*.o`), into a direct edge between the tasks
+    /// on either end. Only tasks with exactly one dependent and one
+    /// dependency, in the same makefile and not linked to another makefile,
+    /// are folded.
+    pub fn fold_intermediates(&mut self, patterns: &[String]) {
+        let patterns = patterns
+            .iter()
+            .map(|p| p.trim_start_matches('*'))
+            .collect::<Vec<&str>>();
+        let linked = self
+            .external_ids()
+            .into_iter()
+            .cloned()
+            .collect::<HashSet<ID>>();
+        let candidate = |m: &Makefile, id: &ID| {
+            !linked.contains(id) && patterns.iter().any(|p| m.tasks[id].name.ends_with(p))
+        };
+        for i in 0..self.makefiles.len() {
+            let m = &self.makefiles[i];
+            // Edges within the makefile, both ways, kept up to date as tasks
+            // are folded.
+            let mut dependencies: HashMap<ID, HashSet<ID>> = HashMap::new();
+            let mut dependents: HashMap<ID, HashSet<ID>> = HashMap::new();
+            for (id, task) in m.tasks.iter() {
+                for dep in task.dependencies.iter().filter_map(|d| m.get_id(d)) {
+                    dependencies
+                        .entry(id.clone())
+                        .or_default()
+                        .insert(dep.clone());
+                    dependents
+                        .entry(dep.clone())
+                        .or_default()
+                        .insert(id.clone());
+                }
+            }
+            let mut queue = m
+                .tasks
+                .keys()
+                .filter(|id| candidate(m, id))
+                .cloned()
+                .collect::<VecDeque<ID>>();
+            let mut removed = HashSet::new();
+            while let Some(id) = queue.pop_front() {
+                let only = |edges: &HashMap<ID, HashSet<ID>>| match edges.get(&id) {
+                    Some(ids) if ids.len() == 1 => ids.iter().next().cloned(),
+                    _ => None,
+                };
+                let (Some(dependency), Some(dependent)) = (only(&dependencies), only(&dependents))
+                else {
+                    continue;
+                };
+                if [&dependency, &dependent].contains(&&id) || dependency == dependent {
+                    continue;
+                }
+                dependencies.remove(&id);
+                dependents.remove(&id);
+                let deps = dependencies
+                    .get_mut(&dependent)
+                    .expect("Dependent has edges");
+                deps.remove(&id);
+                deps.insert(dependency.clone());
+                let deps = dependents
+                    .get_mut(&dependency)
+                    .expect("Dependency has edges");
+                deps.remove(&id);
+                deps.insert(dependent.clone());
+
+                let makefile = &mut self.makefiles[i];
+                let task = makefile.tasks[&id].name.clone();
+                let name = makefile.tasks[&dependency].name.clone();
+                let deps = &mut makefile
+                    .tasks
+                    .get_mut(&dependent)
+                    .expect("Dependent exists")
+                    .dependencies;
+                for dep in deps.iter_mut().filter(|d| **d == task) {
+                    *dep = name.clone();
+                }
+                let mut seen = HashSet::new();
+                deps.retain(|d| seen.insert(d.clone()));
+
+                let mut folded = self
+                    .folded
+                    .remove(&(dependent.clone(), id.clone()))
+                    .unwrap_or_default();
+                folded.push(task);
+                folded.extend(
+                    self.folded
+                        .remove(&(id.clone(), dependency.clone()))
+                        .unwrap_or_default(),
+                );
+                // Another task folded into the same edge earlier, e.g. the
+                // other side of a diamond.
+                self.folded
+                    .entry((dependent.clone(), dependency.clone()))
+                    .or_default()
+                    .extend(folded);
+                removed.insert(id);
+                // Either end may have lost an edge, and be foldable now.
+                let m = &self.makefiles[i];
+                queue.extend(
+                    [dependent, dependency]
+                        .into_iter()
+                        .filter(|id| candidate(m, id)),
+                );
+            }
+            self.makefiles[i]
+                .tasks
+                .retain(|id, _| !removed.contains(id));
+        }
+    }
+
+    /// Folds every chain of tasks with a single dependent and dependency,
+    /// whatever their names.
+    pub fn compact(&mut self) {
+        self.fold_intermediates(&[String::new()]);
+    }
+
+    /// Directories holding a makefile, sorted.
+    pub fn directories(&self) -> Vec<&Path> {
+        let mut dirs = self
+            .makefiles
+            .iter()
+            .filter_map(|m| m.file.parent())
+            .collect::<Vec<&Path>>();
+        dirs.sort();
+        dirs.dedup();
+        dirs
+    }
+
+    /// Invocations of makefiles in another directory, counted per pair of
+    /// (invoking, invoked) directories and sorted.
+    pub fn directory_edges(&self) -> Vec<(&Path, &Path, usize)> {
+        let mut counts: HashMap<(&Path, &Path), usize> = HashMap::new();
+        for external in self.externals.iter() {
+            let from = self
+                .makefiles
+                .iter()
+                .find(|m| m.tasks.contains_key(&external.id))
+                .and_then(|m| m.file.parent());
+            let (Some(from), Some(to)) = (from, external.path.parent()) else {
+                continue;
+            };
+            if from != to {
+                *counts.entry((from, to)).or_default() += 1;
+            }
+        }
+        let mut edges = counts
+            .into_iter()
+            .map(|((from, to), count)| (from, to, count))
+            .collect::<Vec<(&Path, &Path, usize)>>();
+        edges.sort();
+        edges
+    }
+
+    /// Number of edges leaving `id`, to tasks in its own makefile or in
+    /// makefiles it invokes.
+    pub fn out_degree(&self, id: &ID) -> usize {
+        self.dependencies(id)
+            .iter()
+            .filter(|(_, dep)| dep.is_some())
+            .count()
+    }
+
+    /// Ids of tasks without outgoing edges, sorted.
+    pub fn leaves(&self) -> Vec<&ID> {
+        let mut leaves = self
+            .makefiles
+            .iter()
+            .flat_map(|m| m.tasks.keys())
+            .filter(|id| self.out_degree(id) == 0)
+            .collect::<Vec<&ID>>();
+        leaves.sort();
+        leaves
+    }
+
+    pub fn find_task(&self, name: &str) -> Option<&ID> {
+        self.makefiles.iter().find_map(|m| m.get_id(name))
+    }
+
+    /// Names of what `id` depends on or invokes in other makefiles, with the
+    /// id of the task behind each name when there is one.
+    pub fn dependencies(&self, id: &ID) -> Vec<(&str, Option<&ID>)> {
+        let Some(m) = self.makefiles.iter().find(|m| m.tasks.contains_key(id)) else {
+            return Vec::new();
+        };
+        let mut deps = m.tasks[id]
+            .dependencies
+            .iter()
+            .map(|d| (d.as_str(), m.get_id(d)))
+            .collect::<Vec<(&str, Option<&ID>)>>();
+
+        let mut externals = self
+            .externals
+            .iter()
+            .filter(|e| e.id == *id)
+            .collect::<Vec<&External<PathBuf>>>();
+        externals.sort_by(|a, b| a.path.cmp(&b.path));
+        for external in externals {
+            let target = self.makefiles.iter().find(|m| m.file == external.path);
+            deps.extend(
+                external
+                    .tasks
+                    .iter()
+                    .map(|t| (t.as_str(), target.and_then(|m| m.get_id(t)))),
+            );
+        }
+        deps
+    }
+
+    /// Dependency cycles across all makefiles, see [`Makefile::find_cycles`].
+    /// With `externals`, tasks also depend on the tasks they make in other
+    /// makefiles.
+    pub fn find_cycles(&self, externals: bool) -> Vec<Vec<ID>> {
+        let ids = self.makefiles.iter().flat_map(|m| m.tasks.keys());
+        find_cycles(ids, |id| match externals {
+            true => self
+                .dependencies(id)
+                .into_iter()
+                .filter_map(|(_, d)| d)
+                .collect(),
+            false => self
+                .makefiles
+                .iter()
+                .find(|m| m.tasks.contains_key(id))
+                .map(|m| m.dependency_ids(id))
+                .unwrap_or_default(),
+        })
+    }
+
+    /// Checks that every external points at a makefile that exists and
+    /// defines the invoked tasks, returning a description of each problem.
+    pub fn verify_externals(&self) -> Vec<String> {
+        let source = |id: &ID| {
+            let m = self.makefiles.iter().find(|m| m.tasks.contains_key(id));
+            match m {
+                Some(m) => format!("{}: {}", m.file.display(), m.tasks[id].name),
+                None => id.clone(),
+            }
+        };
+
+        let mut problems = self
+            .unresolved
+            .iter()
+            .map(|(e, err)| format!("{}: couldn't resolve {}: {}", source(&e.id), e.path, err))
+            .collect::<Vec<String>>();
+        for external in self.externals.iter() {
+            let Some(m) = self.makefiles.iter().find(|m| m.file == external.path) else {
+                problems.push(format!(
+                    "{}: makefile not walked: {}",
+                    source(&external.id),
+                    external.path.display()
+                ));
+                continue;
+            };
+            for task in external.tasks.iter().filter(|t| m.get_id(t).is_none()) {
+                problems.push(format!(
+                    "{}: no task {} in {}",
+                    source(&external.id),
+                    task,
+                    external.path.display()
+                ));
+            }
+        }
+        problems.sort();
+        problems
+    }
+
+    /// Keeps only tasks whose name matches `filter`, and with `with_deps` also
+    /// the tasks they directly depend on or invoke in other makefiles.
+    pub fn node_filter(&mut self, filter: &Regex, with_deps: bool) {
+        let mut keep = HashSet::new();
+        for m in self.makefiles.iter() {
+            for (id, task) in m.tasks.iter().filter(|(_, t)| filter.is_match(&t.name)) {
+                keep.insert(id.clone());
+                if with_deps {
+                    keep.extend(
+                        task.dependencies
+                            .iter()
+                            .filter_map(|d| m.get_id(d))
+                            .cloned(),
+                    );
+                }
+            }
+        }
+        if with_deps {
+            let invoked = self
+                .externals
+                .iter()
+                .filter(|e| {
+                    self.task(&e.id)
+                        .is_some_and(|(_, t)| filter.is_match(&t.name))
+                })
+                .flat_map(|e| self.external_targets(e))
+                .cloned()
+                .collect::<Vec<ID>>();
+            keep.extend(invoked);
+        }
+        self.retain_tasks(&keep);
+    }
+
+    /// Keeps only the externals invoked from the roots and the files they
+    /// include, along with the makefiles they invoke and their includes,
+    /// dropping anything deeper.
+    pub fn root_only_externals(&mut self) {
+        let top = self
+            .with_includes(&self.roots)
+            .into_iter()
+            .cloned()
+            .collect::<HashSet<PathBuf>>();
+        let top_ids = self
+            .makefiles
+            .iter()
+            .filter(|m| top.contains(&m.file))
+            .flat_map(|m| m.tasks.keys().cloned())
+            .collect::<HashSet<ID>>();
+        self.externals.retain(|e| top_ids.contains(&e.id));
+        self.unresolved.retain(|(e, _)| top_ids.contains(&e.id));
+        self.dynamic.retain(|e| top_ids.contains(&e.id));
+
+        let invoked = self.with_includes(self.externals.iter().map(|e| &e.path));
+        let keep = self
+            .makefiles
+            .iter()
+            .filter(|m| top.contains(&m.file) || invoked.contains(&m.file))
+            .flat_map(|m| m.tasks.keys().cloned())
+            .collect::<HashSet<ID>>();
+        self.retain_tasks(&keep);
+    }
+
+    /// Adds a task for every dependency without a rule in makefiles with a
+    /// `.DEFAULT` rule, depending on `.DEFAULT` since that's what builds it.
+    pub fn link_default(&mut self) {
+        let mut id = IDGen::new("default");
+        for m in self.makefiles.iter_mut() {
+            if m.get_id(".DEFAULT").is_none() {
+                continue;
+            }
+            let mut missing = m
+                .tasks
+                .values()
+                .flat_map(|t| t.dependencies.iter())
+                .filter(|d| m.get_id(d).is_none())
+                .cloned()
+                .collect::<Vec<String>>();
+            missing.sort();
+            missing.dedup();
+            for name in missing {
+                let task = Task {
+                    phony: false,
+                    double_colon: false,
+                    name,
+                    line: 0,
+                    origin_file: m.file.clone(),
+                    dependencies: vec![".DEFAULT".to_string()],
+                    order_only: Vec::new(),
+                    local_vars: Vec::new(),
+                    commands: Vec::new(),
+                };
+                m.tasks.insert(id.next(), task);
+            }
+        }
+    }
+
+    /// Ids of the task named `name` and everything it needs, through
+    /// dependencies, order-only prerequisites and make calls. Empty when
+    /// there's no such task.
+    pub fn reachable_from(&self, name: &str) -> HashSet<ID> {
+        let Some(start) = self.find_task(name) else {
+            return HashSet::new();
+        };
+        let mut reachable = HashSet::from([start.clone()]);
+        let mut queue = VecDeque::from([start]);
+        while let Some(id) = queue.pop_front() {
+            let order_only = self
+                .task(id)
+                .into_iter()
+                .flat_map(|(m, task)| task.order_only.iter().filter_map(|d| m.get_id(d)));
+            let deps = self.dependencies(id).into_iter().filter_map(|(_, d)| d);
+            for dep in deps.chain(order_only) {
+                if reachable.insert(dep.clone()) {
+                    queue.push_back(dep);
+                }
+            }
+        }
+        reachable
+    }
+
+    /// Drops makefiles that can no longer be reached from the roots through
+    /// externals and includes, e.g. after filtering removed the tasks
+    /// invoking them.
+    pub fn prune_unreachable_externals(&mut self) {
+        let mut reachable = self.with_includes(&self.roots);
+        let mut queue = reachable.iter().copied().collect::<Vec<&PathBuf>>();
+        while let Some(file) = queue.pop() {
+            let Some(m) = self.makefiles.iter().find(|m| m.file == *file) else {
+                continue;
+            };
+            let called = self
+                .externals
+                .iter()
+                .filter(|e| m.tasks.contains_key(&e.id))
+                .map(|e| &e.path);
+            for file in self.with_includes(called) {
+                if reachable.insert(file) {
+                    queue.push(file);
+                }
+            }
+        }
+        let keep = self
+            .makefiles
+            .iter()
+            .filter(|m| reachable.contains(&m.file))
+            .flat_map(|m| m.tasks.keys().cloned())
+            .collect::<HashSet<ID>>();
+        self.retain_tasks(&keep);
+    }
+
+    /// Keeps at most `max` tasks, preferring those closest to the roots'
+    /// default goals and make calls in breadth-first order. Returns the number
+    /// of tasks dropped.
+    pub fn truncate(&mut self, max: usize) -> usize {
+        let total = self.makefiles.iter().map(|m| m.tasks.len()).sum::<usize>();
+        if total <= max {
+            return 0;
+        }
+        // Definition order, so the result doesn't depend on hashing.
+        fn by_line(m: &Makefile) -> Vec<&ID> {
+            let mut ids = m.tasks.iter().collect::<Vec<(&ID, &Task)>>();
+            ids.sort_by(|(a_id, a), (b_id, b)| (a.line, a_id).cmp(&(b.line, b_id)));
+            ids.into_iter().map(|(id, _)| id).collect()
+        }
+        let mut roots = Vec::new();
+        for root in self.roots.iter() {
+            let Some(m) = self.makefiles.iter().find(|m| m.file == *root) else {
+                continue;
+            };
+            roots.extend(m.default_goal());
+            roots.extend(
+                by_line(m)
+                    .into_iter()
+                    .filter(|id| self.externals.iter().any(|e| e.id == **id)),
+            );
+        }
+        // Tasks the roots don't reach come last, each with what it reaches.
+        let rest = self.makefiles.iter().flat_map(by_line).map(|id| vec![id]);
+
+        let mut keep = HashSet::new();
+        let mut queue = VecDeque::new();
+        for starts in std::iter::once(roots).chain(rest) {
+            for id in starts {
+                if keep.len() < max && keep.insert(id.clone()) {
+                    queue.push_back(id);
+                }
+            }
+            while let Some(id) = queue.pop_front() {
+                for (_, dep) in self.dependencies(id) {
+                    let Some(dep) = dep else {
+                        continue;
+                    };
+                    if keep.len() == max {
+                        break;
+                    }
+                    if keep.insert(dep.clone()) {
+                        queue.push_back(dep);
+                    }
+                }
+            }
+        }
+        self.retain_tasks(&keep);
+        total - max
+    }
+
+    /// Removes every task not in `keep`, along with the edges to it. Makefiles
+    /// left without tasks are dropped, unless a make call still leads there.
+    pub fn retain_tasks(&mut self, keep: &HashSet<ID>) {
+        for external in std::mem::take(&mut self.externals) {
+            if !keep.contains(&external.id) {
+                continue;
+            }
+            let Some(m) = self.makefiles.iter().find(|m| m.file == external.path) else {
+                continue;
+            };
+            let tasks = external
+                .tasks
+                .iter()
+                .filter(|t| m.get_id(t).is_none_or(|id| keep.contains(id)))
+                .cloned()
+                .collect::<Vec<String>>();
+            // Calls without targets are kept, as they have nothing removed.
+            if !tasks.is_empty() || external.tasks.is_empty() {
+                self.externals.insert(External { tasks, ..external });
+            }
+        }
+
+        for m in self.makefiles.iter_mut() {
+            let mut removed = m
+                .tasks
+                .iter()
+                .filter(|(id, _)| !keep.contains(*id))
+                .map(|(_, t)| t.name.clone())
+                .collect::<HashSet<String>>();
+            m.tasks.retain(|id, _| keep.contains(id));
+            // Names still defined by a kept task, e.g. one of several
+            // double-colon rules, still resolve.
+            for task in m.tasks.values() {
+                removed.remove(&task.name);
+            }
+            for task in m.tasks.values_mut() {
+                task.dependencies.retain(|d| !removed.contains(d));
+                task.order_only.retain(|d| !removed.contains(d));
+            }
+        }
+        self.makefiles
+            .retain(|m| !m.tasks.is_empty() || self.externals.iter().any(|e| e.path == m.file));
+        self.unresolved.retain(|(e, _)| keep.contains(&e.id));
+        self.dynamic.retain(|e| keep.contains(&e.id));
+        self.folded
+            .retain(|(from, to), _| keep.contains(from) && keep.contains(to));
+    }
+
+    /// `files` and every file they include, directly or not.
+    fn with_includes<'a>(
+        &'a self,
+        files: impl IntoIterator<Item = &'a PathBuf>,
+    ) -> HashSet<&'a PathBuf> {
+        let mut out = HashSet::new();
+        let mut queue = files.into_iter().collect::<Vec<&PathBuf>>();
+        while let Some(file) = queue.pop() {
+            if !out.insert(file) {
+                continue;
+            }
+            queue.extend(
+                self.includes
+                    .iter()
+                    .filter(|(including, _)| including == file)
+                    .map(|(_, included)| included),
+            );
+        }
+        out
+    }
+
+    /// The task with `id`, along with the makefile defining it.
+    pub fn task(&self, id: &str) -> Option<(&Makefile, &Task)> {
+        self.makefiles
+            .iter()
+            .find_map(|m| m.tasks.get(id).map(|t| (m, t)))
+    }
+
+    /// Ids of the tasks an external invokes.
+    fn external_targets<'a>(&'a self, external: &'a External<PathBuf>) -> Vec<&'a ID> {
+        match self.makefiles.iter().find(|m| m.file == external.path) {
+            Some(m) => external.tasks.iter().filter_map(|t| m.get_id(t)).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Ids of tasks on either end of an edge between makefiles.
+    fn external_ids(&self) -> HashSet<&ID> {
+        let mut ids = HashSet::new();
+        for external in self.externals.iter() {
+            ids.insert(&external.id);
+            ids.extend(self.external_targets(external));
+        }
+        ids
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::{HashMap, HashSet},
+        path::PathBuf,
+    };
+
+    use regex::Regex;
+
+    use crate::{
+        makefile::{Makefile, Walker},
+        render,
+        testing::fixture,
+    };
+
+    #[test]
+    fn test_recompute() {
+        let root = fixture(
+            "recompute",
+            &[
+                ("Makefile", "all: lib\n\tmake -f sub/Makefile sub\nlib:\n"),
+                ("sub/Makefile", "sub:\n"),
+            ],
+        );
+        let graph = Makefile::walk_from(root.join("Makefile")).unwrap();
+        let (graph, diff) = super::recompute(&graph, &root.join("Makefile")).unwrap();
+        assert!(diff.is_empty(), "{:?}", diff);
+
+        std::fs::write(root.join("Makefile"), "all: lib\nlib: test\ntest:\n").unwrap();
+        let (_, diff) = super::recompute(&graph, &root.join("Makefile")).unwrap();
+        let file = root.join("Makefile").canonicalize().unwrap();
+        let node = |file: &std::path::Path, name: &str| (file.to_path_buf(), name.to_string());
+        assert_eq!(diff.added_nodes, vec![node(&file, "test")]);
+        assert_eq!(
+            diff.added_edges,
+            vec![(node(&file, "lib"), node(&file, "test"))]
+        );
+        let sub = root.join("sub/Makefile").canonicalize().unwrap();
+        assert_eq!(diff.removed_nodes, vec![node(&sub, "sub")]);
+        assert_eq!(
+            diff.removed_edges,
+            vec![(node(&file, "all"), node(&sub, "sub"))]
+        );
+    }
+
+    #[test]
+    fn test_relocate() {
+        let files = [
+            ("Makefile", "all: lib\n\tmake -C sub x\nlib:\n"),
+            ("sub/Makefile", "x:\n"),
+        ];
+        let old = fixture("relocate_old", &files);
+        let new = fixture("relocate_new", &files);
+        let mut graph = Makefile::walk_from(old.join("Makefile")).unwrap();
+        let other = Makefile::walk_from(new.join("Makefile")).unwrap();
+        assert!(!graph.diff(&other).is_empty());
+
+        let (old, new) = (old.canonicalize().unwrap(), new.canonicalize().unwrap());
+        graph.relocate(&old, &new);
+        assert!(graph.diff(&other).is_empty(), "{:?}", graph.diff(&other));
+        assert!(graph.externals.iter().all(|e| e.path.starts_with(&new)));
+    }
+
+    #[test]
+    fn test_find_cycles() {
+        let root = fixture(
+            "find_cycles",
+            &[
+                ("Makefile", "all: lib\n\tmake -C sub x\nlib:\n"),
+                ("sub/Makefile", "x: y\n\tmake -C .. all\ny:\n"),
+            ],
+        );
+        let graph = Makefile::walk_from(root.join("Makefile")).unwrap();
+        assert!(graph.find_cycles(false).is_empty());
+
+        let cycles = graph.find_cycles(true);
+        let names = cycles
+            .iter()
+            .map(|c| {
+                c.iter()
+                    .map(|id| graph.task(id).unwrap().1.name.as_str())
+                    .collect()
+            })
+            .collect::<Vec<Vec<&str>>>();
+        assert_eq!(names, vec![vec!["all", "x"]]);
+    }
+
+    #[test]
+    fn test_reachable_from() {
+        let root = fixture(
+            "reachable_from",
+            &[
+                (
+                    "Makefile",
+                    "deploy: build test | dist\nbuild: lib\ntest: lib\n\tmake -C sub x\nlib:\ndist:\nother: lib\n",
+                ),
+                ("sub/Makefile", "x: y\ny:\nz:\n"),
+            ],
+        );
+        let mut graph = Makefile::walk_from(root.join("Makefile")).unwrap();
+        let keep = graph.reachable_from("deploy");
+        let mut names = keep
+            .iter()
+            .map(|id| graph.task(id).unwrap().1.name.as_str())
+            .collect::<Vec<&str>>();
+        names.sort();
+        assert_eq!(names, ["build", "deploy", "dist", "lib", "test", "x", "y"]);
+        assert!(graph.reachable_from("missing").is_empty());
+
+        graph.retain_tasks(&keep);
+        assert_eq!(graph.makefiles.len(), 2);
+        let mut out = Vec::new();
+        crate::render::write_dot(&mut out, &graph, &Default::default()).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out.matches("[label=\"lib\"]").count(), 1, "{}", out);
+        assert!(!out.contains("\"other\""), "{}", out);
+    }
+
+    #[test]
+    fn test_verify_externals() {
+        let root = fixture(
+            "verify_externals",
+            &[
+                (
+                    "Makefile",
+                    "ok:\n\tmake -f sub/Makefile lib\nbroken:\n\tmake -f missing/Makefile lib\n",
+                ),
+                ("sub/Makefile", "lib:\n"),
+            ],
+        );
+        let graph = Makefile::walk_from(root.join("Makefile")).unwrap();
+        let problems = graph.verify_externals();
+
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert!(
+            problems[0].contains(": broken: couldn't resolve missing/Makefile"),
+            "{:?}",
+            problems
+        );
+
+        std::fs::write(root.join("sub/Makefile"), "other:\n").unwrap();
+        let graph = Makefile::walk_from(root.join("Makefile")).unwrap();
+        let problems = graph.verify_externals();
+        assert_eq!(problems.len(), 2, "{:?}", problems);
+        assert!(
+            problems[1].contains(": ok: no task lib in "),
+            "{:?}",
+            problems
+        );
+    }
+
+    #[test]
+    fn test_node_filter() {
+        let root = fixture(
+            "node_filter",
+            &[
+                (
+                    "Makefile",
+                    "test: build\n\tmake -f sub/Makefile run\ntest-unit: lib\nbuild: lib\nlib:\nother: lib\n",
+                ),
+                ("sub/Makefile", "run: setup\nsetup:\n"),
+            ],
+        );
+        let filter = Regex::new("^test").unwrap();
+        let filtered = |with_deps| {
+            let mut graph = Makefile::walk_from(root.join("Makefile")).unwrap();
+            graph.node_filter(&filter, with_deps);
+            let mut names = graph
+                .makefiles
+                .iter()
+                .flat_map(|m| m.tasks.values().map(|t| t.name.as_str()))
+                .map(str::to_string)
+                .collect::<Vec<String>>();
+            names.sort();
+            (graph, names)
+        };
+
+        let (graph, tasks) = filtered(false);
+        assert_eq!(tasks, vec!["test", "test-unit"]);
+        assert!(graph.externals.is_empty());
+        let m = &graph.makefiles[0];
+        assert!(m.tasks[m.get_id("test").unwrap()].dependencies.is_empty());
+
+        let (graph, tasks) = filtered(true);
+        assert_eq!(tasks, vec!["build", "lib", "run", "test", "test-unit"]);
+        assert_eq!(graph.externals.len(), 1);
+        let sub = &graph.makefiles[1];
+        assert!(sub.tasks[sub.get_id("run").unwrap()]
+            .dependencies
+            .is_empty());
+    }
+
+    #[test]
+    fn test_fold_intermediates() {
+        let root = fixture(
+            "fold_intermediates",
+            &[(
+                "Makefile",
+                "app: src.o\nsrc.o: src.c\n\tcc -c src.c\nsrc.c:\n",
+            )],
+        );
+        let mut graph = Makefile::walk_from(root.join("Makefile")).unwrap();
+        graph.fold_intermediates(&[".o".to_string()]);
+
+        let m = &graph.makefiles[0];
+        let app = m.get_id("app").unwrap();
+        let src = m.get_id("src.c").unwrap();
+        assert_eq!(m.get_id("src.o"), None);
+        assert_eq!(m.tasks[app].dependencies, vec!["src.c"]);
+        assert_eq!(
+            graph.folded_between(app, src),
+            Some(&vec!["src.o".to_string()])
+        );
+
+        let mut out = Vec::new();
+        render::write_dot(&mut out, &graph, &Default::default()).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(
+            out.contains(&format!("\t\t{} -> {}[label=\"src.o\"]\n", app, src)),
+            "{}",
+            out
+        );
+    }
+
+    #[test]
+    fn test_fold_intermediates_chain() {
+        // Folding both sides of the diamond leaves x.o with one dependent.
+        let mut data = "app: a.o b.o\na.o: x.o\nb.o: x.o\nx.o: c0.o\n".to_string();
+        for i in 0..2000 {
+            data += &format!("c{}.o: c{}.o\n", i, i + 1);
+        }
+        data += "c2000.o: src.c\nsrc.c:\n";
+        let files = HashMap::from([(PathBuf::from("/p/Makefile"), data)]);
+        let mut graph = Walker::new("/p/Makefile").files(files).walk().unwrap();
+        graph.fold_intermediates(&[".o".to_string()]);
+
+        let m = &graph.makefiles[0];
+        assert_eq!(m.tasks.len(), 2);
+        let app = m.get_id("app").unwrap();
+        let src = m.get_id("src.c").unwrap();
+        assert_eq!(m.tasks[app].dependencies, vec!["src.c"]);
+        let folded = graph.folded_between(app, src).unwrap();
+        assert_eq!(folded.len(), 2004);
+        assert_eq!(folded[..4], ["a.o", "b.o", "x.o", "c0.o"]);
+        assert_eq!(folded.last().unwrap(), "c2000.o");
+    }
+
+    #[test]
+    fn test_root_only_externals() {
+        let root = fixture(
+            "root_only_externals",
+            &[
+                (
+                    "Makefile",
+                    "all: local\n\tmake -f sub/Makefile lib\nlocal:\n",
+                ),
+                ("sub/Makefile", "lib:\n\tmake -f deep/Makefile deep\n"),
+                ("sub/deep/Makefile", "deep:\n"),
+            ],
+        );
+        let mut graph = Makefile::walk_from(root.join("Makefile")).unwrap();
+        assert_eq!(graph.makefiles.len(), 3);
+        graph.root_only_externals();
+
+        assert_eq!(graph.makefiles.len(), 2);
+        let mut names = graph
+            .makefiles
+            .iter()
+            .flat_map(|m| m.tasks.values().map(|t| t.name.as_str()))
+            .collect::<Vec<&str>>();
+        names.sort();
+        assert_eq!(names, vec!["all", "lib", "local"]);
+        assert_eq!(graph.externals.len(), 1);
+        let external = graph.externals.iter().next().unwrap();
+        assert_eq!(Some(&external.id), graph.find_task("all"));
+
+        // Calls from a second root, and from a file a root includes, are on
+        // the first level too.
+        let root = fixture(
+            "root_only_externals_roots",
+            &[
+                ("Makefile", "include rules.mk\nall:\n"),
+                ("rules.mk", "rules:\n\tmake -f a/Makefile liba\n"),
+                ("a/Makefile", "liba:\n\tmake -f deep/Makefile deep\n"),
+                ("a/deep/Makefile", "deep:\n"),
+                ("other/Makefile", "other:\n\tmake -f ../b/Makefile libb\n"),
+                ("b/Makefile", "libb:\n"),
+            ],
+        );
+        let mut graph = Walker::new(root.join("Makefile"))
+            .add_root(root.join("other/Makefile"))
+            .walk()
+            .unwrap();
+        assert_eq!(graph.makefiles.len(), 6);
+        graph.root_only_externals();
+
+        let mut names = graph
+            .makefiles
+            .iter()
+            .flat_map(|m| m.tasks.values().map(|t| t.name.as_str()))
+            .collect::<Vec<&str>>();
+        names.sort();
+        assert_eq!(names, vec!["all", "liba", "libb", "other", "rules"]);
+        assert_eq!(graph.externals.len(), 2);
+    }
+
+    #[test]
+    fn test_link_default() {
+        let root = fixture(
+            "link_default",
+            &[
+                (
+                    "Makefile",
+                    "all: data.txt\n\tmake -f sub/Makefile lib\n.DEFAULT:\n\ttouch $@\n",
+                ),
+                ("sub/Makefile", "lib: missing.h\n"),
+            ],
+        );
+        let mut graph = Makefile::walk_from(root.join("Makefile")).unwrap();
+        graph.link_default();
+
+        let m = &graph.makefiles[0];
+        let data = m.get_id("data.txt").expect("task for missing dependency");
+        assert_eq!(m.tasks[data].dependencies, vec![".DEFAULT"]);
+        // No .DEFAULT rule there, so the dependency stays missing.
+        assert!(graph.makefiles[1].get_id("missing.h").is_none());
+    }
+
+    #[test]
+    fn test_truncate() {
+        let root = fixture(
+            "truncate",
+            &[
+                (
+                    "Makefile",
+                    "all: aa bb\n\tmake -f sub/Makefile lib\naa: cc\nbb:\ncc:\nzz:\n",
+                ),
+                ("sub/Makefile", "lib: dep\ndep:\n"),
+            ],
+        );
+        let mut graph = Makefile::walk_from(root.join("Makefile")).unwrap();
+        assert_eq!(graph.truncate(10), 0);
+
+        assert_eq!(graph.truncate(6), 1);
+        let mut names = graph
+            .makefiles
+            .iter()
+            .flat_map(|m| m.tasks.values().map(|t| t.name.as_str()))
+            .collect::<Vec<&str>>();
+        names.sort();
+        assert_eq!(names, vec!["aa", "all", "bb", "cc", "dep", "lib"]);
+        let lib = graph.find_task("lib").unwrap();
+        assert_eq!(graph.dependencies(lib).len(), 1);
+
+        // Closer to the default goal wins over defined earlier.
+        let root = fixture(
+            "truncate_bfs",
+            &[("Makefile", "all: late\nearly: deep\ndeep:\nlate:\n")],
+        );
+        let mut graph = Makefile::walk_from(root.join("Makefile")).unwrap();
+        assert_eq!(graph.truncate(2), 2);
+        let mut names = graph.makefiles[0]
+            .tasks
+            .values()
+            .map(|t| t.name.as_str())
+            .collect::<Vec<&str>>();
+        names.sort();
+        assert_eq!(names, vec!["all", "late"]);
+    }
+
+    #[test]
+    fn test_retain_tasks() {
+        let root = fixture(
+            "retain_tasks",
+            &[
+                (
+                    "Makefile",
+                    "all: lib\n\tmake -f sub/Makefile\nlib::\n\techo a\nlib::\n\techo b\n",
+                ),
+                ("sub/Makefile", "X := 1\n"),
+            ],
+        );
+        let mut graph = Makefile::walk_from(root.join("Makefile")).unwrap();
+        let all = graph.find_task("all").unwrap().clone();
+        let lib = graph.makefiles[0]
+            .tasks
+            .iter()
+            .find(|(_, t)| t.commands == ["echo a"])
+            .map(|(id, _)| id.clone())
+            .unwrap();
+        graph.retain_tasks(&HashSet::from([all.clone(), lib.clone()]));
+
+        let m = &graph.makefiles[0];
+        assert_eq!(m.tasks.len(), 2);
+        assert_eq!(m.tasks[&all].dependencies, vec!["lib"]);
+        assert_eq!(graph.dependencies(&all)[0], ("lib", Some(&lib)));
+        // The called makefile has no tasks, but the call still leads there.
+        assert_eq!(graph.makefiles.len(), 2);
+        assert_eq!(graph.externals.len(), 1);
+    }
+
+    #[test]
+    fn test_prune_unreachable_externals() {
+        let root = fixture(
+            "prune_unreachable_externals",
+            &[
+                (
+                    "Makefile",
+                    "all:\n\tmake -f a/Makefile liba\nother:\n\tmake -f b/Makefile libb\n",
+                ),
+                ("a/Makefile", "liba:\n"),
+                ("b/Makefile", "libb:\n\tmake -f ../c/Makefile libc\n"),
+                ("c/Makefile", "libc:\n"),
+            ],
+        );
+        let mut graph = Makefile::walk_from(root.join("Makefile")).unwrap();
+        graph.node_filter(&Regex::new("^(all|lib.*)$").unwrap(), false);
+        assert_eq!(graph.makefiles.len(), 4);
+
+        graph.prune_unreachable_externals();
+        let files = graph
+            .makefiles
+            .iter()
+            .map(|m| m.file.strip_prefix(&root).unwrap().to_str().unwrap())
+            .collect::<Vec<&str>>();
+        assert_eq!(files, vec!["Makefile", "a/Makefile"]);
+        assert_eq!(graph.externals.len(), 1);
+        let external = graph.externals.iter().next().unwrap();
+        assert_eq!(Some(&external.id), graph.find_task("all"));
+
+        // Every root is reached, and the makefiles they include call others.
+        let root = fixture(
+            "prune_unreachable_roots",
+            &[
+                ("Makefile", "include rules.mk\nall:\n"),
+                ("rules.mk", "rules:\n\tmake -f a/Makefile liba\n"),
+                ("a/Makefile", "liba:\n"),
+                ("other/Makefile", "other:\n\tmake -f ../b/Makefile libb\n"),
+                ("b/Makefile", "libb:\n"),
+            ],
+        );
+        let mut graph = Walker::new(root.join("Makefile"))
+            .add_root(root.join("other/Makefile"))
+            .walk()
+            .unwrap();
+        graph.prune_unreachable_externals();
+        let mut files = graph
+            .makefiles
+            .iter()
+            .map(|m| m.file.strip_prefix(&root).unwrap().to_str().unwrap())
+            .collect::<Vec<&str>>();
+        files.sort();
+        assert_eq!(
+            files,
+            vec![
+                "Makefile",
+                "a/Makefile",
+                "b/Makefile",
+                "other/Makefile",
+                "rules.mk"
+            ]
+        );
+    }
+}
@@ -0,0 +1,37 @@
+use std::path::PathBuf;
+
+use thiserror::Error;
+
+pub mod ast;
+mod expand;
+pub mod graph;
+pub mod makefile;
+pub mod parser;
+pub mod render;
+#[cfg(test)]
+mod testing;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("IO error: {0}")]
+    IO(#[from] std::io::Error),
+
+    #[error("IO error at {}: {source}", path.display())]
+    IOAt {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("Parsing error:\n{0}")]
+    ParseErr(String),
+
+    #[error("{0}")]
+    PathErr(String),
+}
+
+impl Error {
+    pub fn from_nom(source: &str, err: nom::error::VerboseError<&str>) -> Self {
+        let str = nom::error::convert_error(source, err);
+        Self::ParseErr(str)
+    }
+}
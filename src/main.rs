@@ -1,42 +1,231 @@
-use std::env::args;
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
 
-use makefile::{IDGen, Makefile};
-use nom::error::VerboseError;
-use thiserror::Error;
+use clap::{Parser, Subcommand, ValueEnum};
+use makedot::{
+    graph::DependencyGraph,
+    makefile::{read_response_file, Makefile, Walker},
+    render,
+};
+use regex::Regex;
 
-mod ast;
-mod makefile;
-mod parser;
+#[derive(Parser, Debug)]
+#[command(
+    about = "Generate dot graphs from Makefiles",
+    version,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
 
-#[derive(Error, Debug)]
-pub enum Error {
-    #[error("IO error: {0}")]
-    IO(#[from] std::io::Error),
+    /// Makefiles to start walking from. @FILE reads more of them from FILE,
+    /// one per line
+    #[arg(required = true)]
+    makefiles: Vec<PathBuf>,
 
-    #[error("Parsing error:\n{0}")]
-    ParseErr(String),
+    /// Output format
+    #[arg(long, value_enum, default_value_t = Format::Dot)]
+    format: Format,
 
-    #[error("{0}")]
-    PathErr(String),
+    /// Write the graph to FILE instead of stdout
+    #[arg(short, long, value_name = "FILE")]
+    output: Option<PathBuf>,
+
+    /// Check that every invoked makefile and task exists, and fail if not
+    #[arg(long)]
+    verify_externals: bool,
+
+    /// Report dependency cycles, also through make calls, and fail if any
+    #[arg(long)]
+    check_cycles: bool,
+
+    /// Warn about file targets that only depend on phony targets
+    #[arg(long)]
+    warn_always_rebuild: bool,
+
+    /// Report targets that look phony but aren't declared .PHONY, and the
+    /// other way around
+    #[arg(long)]
+    lint_phony: bool,
+
+    /// Report targets sharing the exact same recipe
+    #[arg(long)]
+    find_duplicate_recipes: bool,
+
+    /// Warn about prerequisites whose recipe uses an earlier sibling they
+    /// don't depend on, which breaks with make -j
+    #[arg(long)]
+    warn_ordering_assumption: bool,
+
+    /// Print the dependency tree of TARGET as text instead of a graph
+    #[arg(long, value_name = "TARGET")]
+    tree: Option<String>,
+
+    /// Fold chains of intermediate targets with these extensions (e.g. .o,.d)
+    #[arg(long, value_name = "EXTS", value_delimiter = ',')]
+    fold_intermediates: Vec<String>,
+
+    /// Fold all chains of single-dependency tasks into edges counting them
+    #[arg(long)]
+    compact: bool,
+
+    /// Merge rules repeating a target into one node, like make does
+    #[arg(long)]
+    merge_duplicates: bool,
+
+    /// Resolve makefiles invoked with the raw path RAW to PATH instead
+    #[arg(long = "resolve", value_name = "RAW=PATH", value_parser = parse_resolution)]
+    resolutions: Vec<(String, PathBuf)>,
+
+    /// Only follow make invocations from the root makefile, not deeper ones
+    #[arg(long)]
+    root_only_externals: bool,
+
+    /// Link dependencies without a rule to the .DEFAULT rule, if there is one
+    #[arg(long)]
+    link_default: bool,
+
+    /// After filtering, drop makefiles only invoked by removed tasks
+    #[arg(long)]
+    prune_unreachable_externals: bool,
+
+    /// Only keep TARGET and what it needs, also through make calls
+    #[arg(long, value_name = "TARGET")]
+    target: Option<String>,
+
+    /// Only keep tasks whose name matches this regex
+    #[arg(long, value_name = "REGEX")]
+    node_filter: Option<Regex>,
+
+    /// With --node-filter, also keep the direct dependencies of matching tasks
+    #[arg(long, requires = "node_filter")]
+    with_deps: bool,
+
+    /// Place all tasks without dependencies on the same rank
+    #[arg(long)]
+    align_leaves: bool,
+
+    /// Space between ranks in inches, scaled with the graph size by default
+    #[arg(long, value_name = "INCHES")]
+    ranksep: Option<f64>,
+
+    /// Merge included makefiles into the ones including them, grouping their
+    /// tasks by the file they came from
+    #[arg(long)]
+    group_origins: bool,
+
+    /// Only write the clusters and edges, without the digraph wrapper
+    #[arg(long)]
+    fragment: bool,
+
+    /// Fill nodes with a color derived from the makefile defining them
+    #[arg(long)]
+    color_by_makefile: bool,
+
+    /// DOT attributes of phony targets, "shape=box, style=dashed" by default.
+    /// Empty to draw them like other targets
+    #[arg(long, value_name = "ATTRS")]
+    phony_style: Option<String>,
+
+    /// Keep at most N tasks, closest to the default goal first
+    #[arg(long, value_name = "N")]
+    max_nodes: Option<usize>,
+
+    /// Font for graph, node and edge labels
+    #[arg(long, value_name = "NAME")]
+    font_name: Option<String>,
+
+    /// Font size for graph, node and edge labels, in points
+    #[arg(long, value_name = "POINTS")]
+    font_size: Option<f64>,
+
+    /// Draw one node per directory, with edges counting make invocations
+    #[arg(long)]
+    collapse_to_directories: bool,
+
+    /// Highlight what changed since OLD, another version of the first
+    /// makefile, e.g. from the base of a pull request, and dim the rest
+    #[arg(long, value_name = "OLD")]
+    changed_since: Option<PathBuf>,
+
+    /// Don't fall back to environment variables for variables the makefiles
+    /// don't define
+    #[arg(long)]
+    no_env: bool,
+
+    /// Drop the first N path components from makefile labels
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    label_skip: usize,
+
+    /// Precede nodes and edges with a comment naming the rule they came from
+    #[arg(long)]
+    annotate_source: bool,
 }
 
-impl Error {
-    pub fn from_nom(source: &str, err: nom::error::VerboseError<&str>) -> Self {
-        let str = nom::error::convert_error(source, err);
-        Self::ParseErr(str)
-    }
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// List the variables of a makefile with their raw and resolved values
+    Vars {
+        /// Makefile to read the variables of
+        makefile: PathBuf,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Format {
+    Dot,
+    Plantuml,
+    Html,
+    /// Adjacency matrix as CSV
+    Matrix,
+    /// Makefiles, their tasks and calls into other makefiles as JSON
+    Json,
 }
 
 fn main() {
-    // TODO: clap arg parser
-    let path = args().nth(1).unwrap_or_else(|| {
-        eprintln!("Usage: {} <makefile>", args().next().unwrap());
+    let args = Args::parse();
+
+    if let Some(Command::Vars { makefile }) = &args.command {
+        print_vars(makefile);
+        return;
+    }
+    let mut makefiles = Vec::new();
+    for arg in args.makefiles {
+        match arg.to_str().and_then(|a| a.strip_prefix('@')) {
+            Some(file) => match read_response_file(Path::new(file)) {
+                Ok(paths) => makefiles.extend(paths),
+                Err(err) => {
+                    eprintln!("Error reading response file:\n{}", err);
+                    std::process::exit(1);
+                }
+            },
+            None => makefiles.push(arg),
+        }
+    }
+    let Some((first, rest)) = makefiles.split_first() else {
+        eprintln!("No makefiles to start from");
         std::process::exit(1);
-    });
+    };
 
-    eprintln!("Starting at {}", path);
+    for makefile in makefiles.iter() {
+        eprintln!("Starting at {}", makefile.display());
+    }
 
-    let (makefiles, externals) = match Makefile::walk_from(path) {
+    let resolutions = args.resolutions.into_iter().collect::<HashMap<_, _>>();
+    let walker = rest
+        .iter()
+        .fold(Walker::new(first), |walker, root| walker.add_root(root))
+        .merge_duplicates(args.merge_duplicates)
+        .use_env(!args.no_env)
+        .merge_includes(args.group_origins)
+        .resolutions(resolutions.clone());
+    let mut graph = match walker.walk() {
         Ok(v) => v,
         Err(err) => {
             eprintln!("Error walking makefile:\n{}", err);
@@ -44,42 +233,280 @@ fn main() {
         }
     };
 
-    let mut id = IDGen::new("cluster_");
-    println!("digraph G {{\n\tranksep=3");
-    for makefile in makefiles.iter() {
-        println!(
-            "\tsubgraph {} {{\n\t\tlabel=\"{}\"",
-            id.next(),
-            makefile.file.display()
-        );
-
-        for (id, task) in &makefile.tasks {
-            println!("\t\t{}[label=\"{}\"]", id, task.name);
-            for dep in task.dependencies.iter() {
-                match makefile.get_id(dep) {
-                    Some(dep_id) => println!("\t\t{} -> {}", id, dep_id),
-                    None => eprintln!("Bad dependency: {}", dep),
-                }
+    let changes = args.changed_since.map(|old| {
+        let walker = Walker::new(old)
+            .merge_duplicates(args.merge_duplicates)
+            .use_env(!args.no_env)
+            .merge_includes(args.group_origins)
+            .resolutions(resolutions);
+        let mut before = match walker.walk() {
+            Ok(v) => v,
+            Err(err) => {
+                eprintln!("Error walking old makefile:\n{}", err);
+                std::process::exit(1);
             }
+        };
+        // Compare the versions by paths relative to their root makefiles.
+        let dir = |graph: &DependencyGraph| graph.makefiles[0].file.parent().map(Path::to_path_buf);
+        if let (Some(from), Some(to)) = (dir(&before), dir(&graph)) {
+            before.relocate(&from, &to);
+        }
+        before.diff(&graph)
+    });
+
+    if args.verify_externals {
+        let problems = graph.verify_externals();
+        if !problems.is_empty() {
+            eprintln!("Broken externals:");
+            for problem in problems {
+                eprintln!("\t{}", problem);
+            }
+            std::process::exit(1);
         }
-        println!("\t}}");
     }
 
-    for external in externals.iter() {
-        let m = match makefiles.iter().find(|m| m.file == external.path) {
-            Some(v) => v,
-            None => {
-                eprintln!("External makefile not found: {:?}", external.path);
-                continue;
+    if args.check_cycles {
+        let cycles = graph.find_cycles(true);
+        if !cycles.is_empty() {
+            eprintln!("Dependency cycles:");
+            for mut cycle in cycles {
+                cycle.push(cycle[0].clone());
+                let tasks = cycle
+                    .iter()
+                    .map(|id| match graph.task(id) {
+                        Some((m, task)) => format!("{}: {}", m.file.display(), task.name),
+                        None => id.clone(),
+                    })
+                    .collect::<Vec<String>>();
+                eprintln!("\t{}", tasks.join(" -> "));
             }
-        };
+            std::process::exit(1);
+        }
+    }
 
-        for task in external.tasks.iter() {
-            match m.get_id(task) {
-                Some(task_id) => println!("\t{} -> {}", external.id, task_id),
-                None => eprintln!("External task not found: {}", task),
+    if args.warn_always_rebuild {
+        for m in graph.makefiles.iter() {
+            for name in m.always_rebuilt() {
+                eprintln!(
+                    "{}: {} only depends on phony targets and is always rebuilt",
+                    m.file.display(),
+                    name
+                );
             }
         }
     }
-    println!("}}");
+
+    if args.lint_phony {
+        for m in graph.makefiles.iter() {
+            let (undeclared, misdeclared) = m.lint_phony();
+            for name in undeclared {
+                eprintln!(
+                    "{}: {} looks phony but isn't in .PHONY",
+                    m.file.display(),
+                    name
+                );
+            }
+            for name in misdeclared {
+                eprintln!(
+                    "{}: {} is in .PHONY but looks like a file",
+                    m.file.display(),
+                    name
+                );
+            }
+        }
+    }
+
+    if args.find_duplicate_recipes {
+        for m in graph.makefiles.iter() {
+            for names in m.duplicate_recipes() {
+                eprintln!(
+                    "{}: identical recipes for {}",
+                    m.file.display(),
+                    names.join(", ")
+                );
+            }
+        }
+    }
+
+    if args.warn_ordering_assumption {
+        for m in graph.makefiles.iter() {
+            for (target, dep, sibling) in m.ordering_assumptions() {
+                eprintln!(
+                    "{}: {} uses {} without depending on it, relying on the order of {}'s prerequisites",
+                    m.file.display(),
+                    dep,
+                    sibling,
+                    target
+                );
+            }
+        }
+    }
+
+    if args.link_default {
+        graph.link_default();
+    }
+    if args.root_only_externals {
+        graph.root_only_externals();
+    }
+    if let Some(target) = &args.target {
+        let keep = graph.reachable_from(target);
+        if keep.is_empty() {
+            eprintln!("No task named {}", target);
+            std::process::exit(1);
+        }
+        graph.retain_tasks(&keep);
+    }
+    if let Some(filter) = &args.node_filter {
+        graph.node_filter(filter, args.with_deps);
+    }
+    if args.prune_unreachable_externals {
+        graph.prune_unreachable_externals();
+    }
+    if !args.fold_intermediates.is_empty() {
+        graph.fold_intermediates(&args.fold_intermediates);
+    }
+    if args.compact {
+        graph.compact();
+    }
+    let truncated = match args.max_nodes {
+        Some(max) => graph.truncate(max),
+        None => 0,
+    };
+    if truncated > 0 {
+        eprintln!("Left out {} tasks to stay within --max-nodes", truncated);
+    }
+
+    let options = render::Options {
+        label_skip: args.label_skip,
+        annotate_source: args.annotate_source,
+        align_leaves: args.align_leaves,
+        ranksep: args.ranksep,
+        compact: args.compact,
+        group_origins: args.group_origins,
+        fragment: args.fragment,
+        color_by_makefile: args.color_by_makefile,
+        phony_style: args.phony_style,
+        font_name: args.font_name,
+        font_size: args.font_size,
+        truncated,
+        changes,
+    };
+    let mut out: BufWriter<Box<dyn Write>> = BufWriter::new(match &args.output {
+        Some(path) => match File::create(path) {
+            Ok(file) => Box::new(file),
+            Err(err) => {
+                eprintln!("Error creating {}:\n{}", path.display(), err);
+                std::process::exit(1);
+            }
+        },
+        None => Box::new(io::stdout().lock()),
+    });
+    let res = write_graph(
+        &mut out,
+        &graph,
+        args.tree.as_deref(),
+        args.collapse_to_directories,
+        args.format,
+        &options,
+    )
+    .and_then(|_| out.flush());
+    if let Err(err) = res {
+        eprintln!("Error writing graph:\n{}", err);
+        std::process::exit(1);
+    }
+}
+
+/// Writes `graph` to `out`, as the dependency tree of `tree` if given, else
+/// in `format`.
+fn write_graph(
+    out: &mut impl Write,
+    graph: &DependencyGraph,
+    tree: Option<&str>,
+    collapse_to_directories: bool,
+    format: Format,
+    options: &render::Options,
+) -> io::Result<()> {
+    match (tree, format) {
+        (Some(target), _) => render::write_tree(out, graph, target),
+        (None, _) if collapse_to_directories => render::write_directories(out, graph, options),
+        (None, Format::Dot) => render::write_dot(out, graph, options),
+        (None, Format::Plantuml) => render::write_plantuml(out, graph, options),
+        (None, Format::Html) => render::write_html(out, graph, options),
+        (None, Format::Matrix) => render::write_matrix(out, graph),
+        (None, Format::Json) => render::write_json(out, graph),
+    }
+}
+
+fn print_vars(path: &Path) {
+    let makefile = match Makefile::open(path) {
+        Ok(v) => v,
+        Err(err) => {
+            eprintln!("Error parsing makefile:\n{}", err);
+            std::process::exit(1);
+        }
+    };
+    for (name, raw, resolved) in makefile.resolved_variables() {
+        println!("{} = {}", name, raw);
+        if resolved != raw {
+            println!("\t=> {}", resolved);
+        }
+    }
+}
+
+fn parse_resolution(arg: &str) -> Result<(String, PathBuf), String> {
+    match arg.split_once('=') {
+        Some((raw, path)) => Ok((raw.to_string(), PathBuf::from(path))),
+        None => Err(format!("expected RAW=PATH, got {:?}", arg)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, path::PathBuf};
+
+    use makedot::makefile::Walker;
+
+    use super::{write_graph, Format};
+
+    #[test]
+    fn test_write_graph() {
+        let files = HashMap::from([
+            (
+                PathBuf::from("/p/Makefile"),
+                "all: lib\n\tmake -C sub\nlib:\n".to_string(),
+            ),
+            (PathBuf::from("/p/sub/Makefile"), "sub:\n".to_string()),
+        ]);
+        let graph = Walker::new("/p/Makefile").files(files).walk().unwrap();
+        let cases = [
+            (None, false, Format::Dot, "digraph G {\n\tranksep="),
+            (None, false, Format::Plantuml, "@startuml\n"),
+            (None, false, Format::Html, "<!DOCTYPE html>\n"),
+            (None, false, Format::Matrix, ",all,lib,sub\n"),
+            (None, false, Format::Json, "{\n  \"makefiles\": [\n"),
+            (
+                None,
+                true,
+                Format::Matrix,
+                "digraph G {\n\tdir0[label=\"/p\"]\n",
+            ),
+            (Some("all"), false, Format::Dot, "all\n├── lib\n└── sub\n"),
+            (Some("lib"), true, Format::Json, "lib\n"),
+        ];
+
+        for (i, (tree, collapse, format, expected)) in cases.into_iter().enumerate() {
+            let mut out = Vec::new();
+            write_graph(
+                &mut out,
+                &graph,
+                tree,
+                collapse,
+                format,
+                &Default::default(),
+            )
+            .unwrap();
+            let out = String::from_utf8(out).unwrap();
+            assert!(out.starts_with(expected), "case {:02}: {}", i, out);
+        }
+    }
 }
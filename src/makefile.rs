@@ -1,18 +1,51 @@
 use std::{
+    borrow::Borrow,
+    cell::RefCell,
     collections::{HashMap, HashSet, VecDeque},
-    path::{self, Path, PathBuf},
+    hash::Hash,
+    io,
+    ops::Index,
+    path::{Component, Path, PathBuf},
     sync::OnceLock,
 };
 
-use regex::Regex;
+use serde::{Serialize, Serializer};
 
 use crate::{
     ast::{self, Parse as _},
+    expand::Expander,
+    graph::DependencyGraph,
     parser, Error,
 };
 
-type ID = String;
-type Variables = HashMap<String, String>;
+pub type ID = String;
+/// Variables by name, with the operator that decides how they expand.
+type Variables = HashMap<String, Value>;
+
+/// Value of a variable and the operator it was assigned with. `+=` and `?=`
+/// keep the operator of the assignment they add to, and are stored as `=`
+/// for new variables.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Value {
+    pub op: String,
+    /// Already expanded for immediate assignments, raw otherwise.
+    pub value: String,
+}
+
+impl Value {
+    pub fn new(op: &str, value: impl Into<String>) -> Self {
+        Self {
+            op: op.to_string(),
+            value: value.into(),
+        }
+    }
+
+    /// Whether the value was expanded when assigned, with `:=` or `::=`,
+    /// rather than each time it's used.
+    pub fn is_immediate(&self) -> bool {
+        matches!(self.op.as_str(), ":=" | "::=")
+    }
+}
 
 macro_rules! regex {
     ($re:literal $(,)?) => {{
@@ -27,6 +60,7 @@ impl IDGen {
         Self(prefix, 0)
     }
 
+    #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> ID {
         let id = format!("{}{}", self.0, self.1);
         self.1 += 1;
@@ -34,31 +68,310 @@ impl IDGen {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct Task {
     pub phony: bool,
+    /// Defined by a `target:: deps` rule, which keeps its own recipe even
+    /// when the target has other rules.
+    #[serde(skip)]
+    pub double_colon: bool,
     pub name: String,
+    /// Line of the rule defining the task.
+    #[serde(skip)]
+    pub line: usize,
+    /// File the rule was read from, which differs from the makefile's own
+    /// once other makefiles are merged into it.
+    #[serde(skip)]
+    pub origin_file: PathBuf,
     pub dependencies: Vec<String>,
+    /// Prerequisites after `|`, which only have to exist before the task
+    /// runs.
+    pub order_only: Vec<String>,
     pub commands: Vec<String>,
+    /// Target-specific variables as (name, operator, raw value), in the
+    /// order they're assigned.
+    #[serde(skip)]
+    pub local_vars: Vec<(String, String, String)>,
+}
+
+impl Task {
+    /// Guesses whether the task is phony: it has a conventional phony name,
+    /// or a recipe that never mentions the file it should produce.
+    pub fn looks_phony(&self) -> bool {
+        const NAMES: [&str; 10] = [
+            "all",
+            "check",
+            "clean",
+            "distclean",
+            "help",
+            "install",
+            "lint",
+            "run",
+            "test",
+            "uninstall",
+        ];
+        if NAMES.contains(&self.name.as_str()) {
+            return true;
+        }
+        let file_like = self.name.contains(['.', '/', '$']);
+        let writes_target = self
+            .commands
+            .iter()
+            .any(|c| c.contains("$@") || c.split_whitespace().any(|w| w == self.name));
+        !file_like && !self.commands.is_empty() && !writes_target
+    }
+}
+
+/// Tasks by id, iterated in the order they were added, which follows the
+/// order of the rules in the makefile. Tasks can also be looked up by name,
+/// which mustn't be changed through [`Tasks::get_mut`] or the like.
+#[derive(Debug, Default)]
+pub struct Tasks {
+    tasks: Vec<(ID, Task)>,
+    /// Position of each task in `tasks`.
+    index: HashMap<ID, usize>,
+    /// Id of the last task with each name.
+    names: HashMap<String, ID>,
+}
+
+impl Tasks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.tasks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+
+    pub fn contains_key<Q>(&self, id: &Q) -> bool
+    where
+        ID: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.index.contains_key(id)
+    }
+
+    pub fn get<Q>(&self, id: &Q) -> Option<&Task>
+    where
+        ID: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.index.get(id).map(|&i| &self.tasks[i].1)
+    }
+
+    pub fn get_mut<Q>(&mut self, id: &Q) -> Option<&mut Task>
+    where
+        ID: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.index.get(id).map(|&i| &mut self.tasks[i].1)
+    }
+
+    /// Adds a task after the others, or replaces the one with the same id in
+    /// place.
+    pub fn insert(&mut self, id: ID, task: Task) -> Option<Task> {
+        match self.index.get(&id) {
+            Some(&i) => {
+                let old = std::mem::replace(&mut self.tasks[i].1, task);
+                self.reindex();
+                Some(old)
+            }
+            None => {
+                self.index.insert(id.clone(), self.tasks.len());
+                self.names.insert(task.name.clone(), id.clone());
+                self.tasks.push((id, task));
+                None
+            }
+        }
+    }
+
+    /// Id of the task named `name`, the last one defined when there are
+    /// several, as make uses the last recipe.
+    pub fn id_of(&self, name: &str) -> Option<&ID> {
+        self.names.get(name)
+    }
+
+    pub fn remove<Q>(&mut self, id: &Q) -> Option<Task>
+    where
+        ID: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let i = self.index.remove(id)?;
+        let (_, task) = self.tasks.remove(i);
+        self.reindex();
+        Some(task)
+    }
+
+    pub fn retain(&mut self, mut keep: impl FnMut(&ID, &mut Task) -> bool) {
+        self.tasks.retain_mut(|(id, task)| keep(id, task));
+        self.reindex();
+    }
+
+    fn reindex(&mut self) {
+        self.index = self
+            .tasks
+            .iter()
+            .enumerate()
+            .map(|(i, (id, _))| (id.clone(), i))
+            .collect();
+        self.names = self
+            .tasks
+            .iter()
+            .map(|(id, task)| (task.name.clone(), id.clone()))
+            .collect();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&ID, &Task)> {
+        self.tasks.iter().map(|(id, task)| (id, task))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &ID> {
+        self.tasks.iter().map(|(id, _)| id)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &Task> {
+        self.tasks.iter().map(|(_, task)| task)
+    }
+
+    pub fn values_mut(&mut self) -> impl Iterator<Item = &mut Task> {
+        self.tasks.iter_mut().map(|(_, task)| task)
+    }
+
+    pub fn into_values(self) -> impl Iterator<Item = Task> {
+        self.tasks.into_iter().map(|(_, task)| task)
+    }
+}
+
+impl<Q> Index<&Q> for Tasks
+where
+    ID: Borrow<Q>,
+    Q: Hash + Eq + ?Sized,
+{
+    type Output = Task;
+
+    fn index(&self, id: &Q) -> &Task {
+        self.get(id).expect("No task with this id")
+    }
+}
+
+/// Serializes as a list of the tasks in order, each with its id.
+impl Serialize for Tasks {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct Entry<'a> {
+            id: &'a ID,
+            #[serde(flatten)]
+            task: &'a Task,
+        }
+        serializer.collect_seq(self.iter().map(|(id, task)| Entry { id, task }))
+    }
+}
+
+impl<'a> IntoIterator for &'a Tasks {
+    type Item = (&'a ID, &'a Task);
+    type IntoIter =
+        std::iter::Map<std::slice::Iter<'a, (ID, Task)>, fn(&'a (ID, Task)) -> Self::Item>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.tasks.iter().map(|(id, task)| (id, task))
+    }
 }
 
-#[derive(Debug)]
+/// Serializes as its file and tasks, which is all the graph is drawn from.
+#[derive(Debug, Serialize)]
 pub struct Makefile {
     pub file: PathBuf,
+    #[serde(skip)]
     pub variables: Variables,
-    pub tasks: HashMap<ID, Task>,
+    pub tasks: Tasks,
+    /// Set by `.EXPORT_ALL_VARIABLES`, every variable is passed on to
+    /// sub-makes.
+    #[serde(skip)]
+    pub export_all: bool,
+    /// Variables marked with `export`, and not `unexport`ed since.
+    #[serde(skip)]
+    pub exported: HashSet<String>,
+    /// Variables assigned with `!=`. Shell commands aren't run, so their
+    /// value is the command itself rather than its output.
+    #[serde(skip)]
+    pub shell_assigned: HashSet<String>,
+    #[serde(skip)]
+    pub includes: Vec<Include>,
+    /// Includes leading back to a file that includes them, each as the chain
+    /// of files from that one back to itself. The last include isn't
+    /// followed.
+    #[serde(skip)]
+    pub include_cycles: Vec<Vec<PathBuf>>,
+    /// Environment variables looked up for variables the makefile doesn't
+    /// define, empty unless the walker was given some.
+    #[serde(skip)]
+    pub env: HashMap<String, String>,
+    /// Warnings already printed, as the same values are expanded again and
+    /// again.
+    #[serde(skip)]
+    pub(crate) warned: RefCell<HashSet<String>>,
+}
+
+/// What [`Makefile::from_terms`] keeps track of while reading a makefile and
+/// the files it includes.
+struct Reader<'r> {
+    id: &'r mut IDGen,
+    external: &'r mut HashSet<External<VarStr>>,
+    walker: &'r Walker,
+    /// Target-specific variables, by target name, given to the tasks at the
+    /// end since their rules may come later.
+    local_vars: HashMap<String, Vec<(String, String, String)>>,
+    /// The file being read, after the files including it.
+    files: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Include {
+    /// Path of the included makefile, expanded with the variables defined
+    /// before the `include`.
+    pub path: String,
+    /// Set for `-include` and `sinclude`, which ignore missing files.
+    pub optional: bool,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+/// Ids of tasks depending on each other in a circle, the last on the first.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cycle(pub Vec<ID>);
+
+/// A string with unresolved variable references, see
+/// [`Makefile::resolve_vars`].
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct VarStr(String);
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+impl From<&str> for VarStr {
+    fn from(str: &str) -> Self {
+        Self(str.to_string())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
 pub struct External<T> {
     pub path: T,
     pub id: ID,
+    /// Targets make is called with. Empty for its default goal, until the
+    /// walk fills that in.
     pub tasks: Vec<String>,
 }
 
+impl VarStr {
+    /// Whether this is a shell variable (e.g. `"$OUT"` or `$$dir`), only
+    /// known once the recipe runs.
+    fn is_shell_variable(&self) -> bool {
+        let str = self.0.trim_matches(['"', '\'']);
+        let str = str.strip_prefix("$$").or_else(|| str.strip_prefix('$'));
+        str.is_some_and(|s| s.starts_with(|c: char| c.is_alphanumeric() || c == '_'))
+    }
+}
+
 impl<T> External<T> {
     fn map_path<U>(self, f: impl FnOnce(T) -> U) -> External<U> {
         External {
@@ -69,61 +382,732 @@ impl<T> External<T> {
     }
 }
 
-impl Makefile {
-    pub fn get_id(&self, name: &str) -> Option<&ID> {
-        self.tasks
-            .iter()
-            .find(|(_, t)| t.name == name)
-            .map(|(id, _)| id)
+type TermTransform = Box<dyn for<'a> Fn(&Path, Vec<ast::Term<'a>>) -> Vec<ast::Term<'a>>>;
+
+/// Walks a makefile and every makefile it invokes through `make`.
+pub struct Walker {
+    roots: Vec<PathBuf>,
+    term_transform: Option<TermTransform>,
+    merge_duplicates: bool,
+    resolutions: HashMap<String, PathBuf>,
+    home: Option<PathBuf>,
+    files: Option<HashMap<PathBuf, String>>,
+    env: HashMap<String, String>,
+    merge_includes: bool,
+}
+
+impl Default for Walker {
+    /// A walker without roots, e.g. for the settings of
+    /// [`Makefile::from_terms`].
+    fn default() -> Self {
+        Self {
+            roots: Vec::new(),
+            term_transform: None,
+            merge_duplicates: false,
+            resolutions: HashMap::new(),
+            home: std::env::var_os("HOME").map(PathBuf::from),
+            files: None,
+            env: HashMap::new(),
+            merge_includes: false,
+        }
     }
-    pub fn walk_from(
-        path: impl AsRef<Path>,
-    ) -> Result<(Vec<Makefile>, HashSet<External<PathBuf>>), crate::Error> {
-        let path = path.as_ref().to_path_buf();
+}
+
+impl Walker {
+    pub fn new(root: impl AsRef<Path>) -> Self {
+        Self {
+            roots: vec![root.as_ref().to_path_buf()],
+            ..Self::default()
+        }
+    }
+
+    /// Also walks from `root`, once the roots given before have been
+    /// queued.
+    pub fn add_root(mut self, root: impl AsRef<Path>) -> Self {
+        self.roots.push(root.as_ref().to_path_buf());
+        self
+    }
+
+    /// Rewrites each file's parsed terms before they are turned into a
+    /// [`Makefile`].
+    pub fn term_transform(
+        mut self,
+        transform: impl for<'a> Fn(&Path, Vec<ast::Term<'a>>) -> Vec<ast::Term<'a>> + 'static,
+    ) -> Self {
+        self.term_transform = Some(Box::new(transform));
+        self
+    }
+
+    /// Merges rules repeating a target into a single task, like make does,
+    /// instead of giving each rule its own node.
+    pub fn merge_duplicates(mut self, merge: bool) -> Self {
+        self.merge_duplicates = merge;
+        self
+    }
+
+    /// Resolves externals invoked with one of these raw paths to the given
+    /// makefile instead of looking for it on disk, e.g. for subdirectories
+    /// that aren't checked out.
+    pub fn resolutions(mut self, resolutions: HashMap<String, PathBuf>) -> Self {
+        self.resolutions = resolutions;
+        self
+    }
+
+    /// Home directory used for paths starting with `~`, `$HOME` by default.
+    pub fn home(mut self, home: impl AsRef<Path>) -> Self {
+        self.home = Some(home.as_ref().to_path_buf());
+        self
+    }
+
+    /// Falls back to the process environment for variables the makefiles
+    /// don't define, like make does. Off by default, so the graph doesn't
+    /// depend on where it's drawn.
+    pub fn use_env(self, use_env: bool) -> Self {
+        let env = match use_env {
+            true => std::env::vars_os()
+                .filter_map(|(name, value)| {
+                    Some((name.into_string().ok()?, value.into_string().ok()?))
+                })
+                .collect(),
+            false => HashMap::new(),
+        };
+        self.env(env)
+    }
+
+    /// Like [`Walker::use_env`], with `env` standing in for the process
+    /// environment.
+    pub fn env(mut self, env: HashMap<String, String>) -> Self {
+        self.env = env;
+        self
+    }
+
+    /// Reads included makefiles in place of the `include`, like make does,
+    /// instead of walking them as makefiles of their own. Their tasks keep
+    /// the file they came from in [`Task::origin_file`].
+    pub fn merge_includes(mut self, merge: bool) -> Self {
+        self.merge_includes = merge;
+        self
+    }
+
+    /// Reads makefiles from `files`, by path, instead of the disk. Paths are
+    /// then normalized lexically rather than canonicalized, so nothing they
+    /// point to has to exist.
+    pub fn files(mut self, files: HashMap<PathBuf, String>) -> Self {
+        let files = files
+            .into_iter()
+            .map(|(path, data)| (normalize(&path), data))
+            .collect();
+        self.files = Some(files);
+        self
+    }
+
+    pub fn walk(&self) -> Result<DependencyGraph, crate::Error> {
+        self.walk_with_observer(|_| ())
+    }
+
+    /// Like [`Walker::walk`], calling `observer` with each external as soon
+    /// as its makefile is resolved.
+    pub fn walk_with_observer(
+        &self,
+        mut observer: impl FnMut(&External<PathBuf>),
+    ) -> Result<DependencyGraph, crate::Error> {
         let mut out = Vec::new();
         let mut idgen = IDGen::new("task");
         let mut external: HashSet<External<PathBuf>> = HashSet::new();
-        let mut paths = VecDeque::from([path]);
+        let mut paths = VecDeque::from(self.roots.clone());
+        let mut roots = Vec::new();
+        let mut includes = Vec::new();
+        let mut unresolved = Vec::new();
+        let mut dynamic = Vec::new();
+        // Variables exported to each makefile by the first one calling it.
+        let mut inherited: HashMap<PathBuf, Vec<(String, String)>> = HashMap::new();
+        // The makefile each one was first included by.
+        let mut included_by: HashMap<PathBuf, PathBuf> = HashMap::new();
 
         while let Some(path) = paths.pop_front() {
-            eprintln!("Parsing {}", path.display());
             let mut exts = HashSet::new();
-            let data = std::fs::read_to_string(&path)?;
-            let terms = parser::Makefile::parse(&data).map_err(|e| Error::from_nom(&data, e))?;
-            let m = Makefile::from_terms(&mut idgen, &mut exts, path, terms);
+            let env = inherited.remove(&path).unwrap_or_default();
+            let mut m = self.parse(&mut idgen, &mut exts, path, &env)?;
+            // The roots are queued first.
+            if out.len() < self.roots.len() {
+                roots.push(m.file.clone());
+            }
+            let exported = m.exported_variables();
+            // Sorted, so makefiles are queued in the same order every time.
+            let mut exts = exts.into_iter().collect::<Vec<_>>();
+            exts.sort();
             let exts = exts.iter().filter_map(|e| {
                 let path = &e.path;
-                let path = match m.resolve_makefile(path) {
+                // Paths come from expanded recipe lines, with their dollars
+                // escaped again, while these are reported as the shell sees
+                // them.
+                let raw = || e.clone().map_path(|p| p.0.replace("$$", "$"));
+                if path.is_shell_variable() {
+                    dynamic.push(raw());
+                    return None;
+                }
+                let resolved = match self.resolutions.get(&raw().path) {
+                    Some(p) => Ok(p.clone()),
+                    None => self.resolve(&m, path),
+                };
+                let path = match resolved {
                     Ok(p) => p,
                     Err(err) => {
-                        eprintln!("Couldn't resolve makefile: {}, {}", path.0, err);
+                        eprintln!("Couldn't resolve makefile: {}, {}", raw().path, err);
+                        unresolved.push((raw(), err));
                         return None;
                     }
                 };
                 if !(paths.contains(&path) || out.iter().any(|m: &Makefile| m.file == path)) {
                     paths.push_back(path.clone());
+                    inherited.insert(path.clone(), exported.clone());
                 }
 
-                Some(e.clone().map_path(|_| path))
+                let e = e.clone().map_path(|_| path);
+                observer(&e);
+                Some(e)
             });
             external.extend(exts);
+            let mut cycles = Vec::new();
+            for include in m.includes.iter().filter(|_| !self.merge_includes) {
+                match self.resolve(&m, &VarStr(include.path.clone())) {
+                    Ok(path) => {
+                        includes.push((m.file.clone(), path.clone()));
+                        // This makefile and the ones including it, closest
+                        // first.
+                        let mut chain = vec![&m.file];
+                        while let Some(file) = included_by.get(chain[chain.len() - 1]) {
+                            chain.push(file);
+                        }
+                        if let Some(i) = chain.iter().position(|f| **f == path) {
+                            let mut cycle = chain[..=i]
+                                .iter()
+                                .rev()
+                                .map(|&f| f.clone())
+                                .collect::<Vec<PathBuf>>();
+                            cycle.push(path);
+                            cycles.push(cycle);
+                        } else if !(paths.contains(&path) || out.iter().any(|m| m.file == path)) {
+                            included_by.insert(path.clone(), m.file.clone());
+                            paths.push_back(path);
+                        }
+                    }
+                    Err(_) if include.optional => (),
+                    Err(err) => eprintln!("Couldn't resolve include: {}, {}", include.path, err),
+                }
+            }
+            for cycle in cycles {
+                m.include_cycle(cycle);
+            }
             out.push(m);
         }
 
-        Ok((out, external))
+        // Calls without targets build the default goal of the makefile.
+        let external = external
+            .into_iter()
+            .map(|mut e| {
+                if e.tasks.is_empty() {
+                    let m = out.iter().find(|m| m.file == e.path);
+                    let goal = m.and_then(|m| Some(&m.tasks[m.default_goal()?].name));
+                    e.tasks.extend(goal.cloned());
+                }
+                e
+            })
+            .collect();
+
+        Ok(DependencyGraph {
+            makefiles: out,
+            roots,
+            includes,
+            externals: external,
+            unresolved,
+            dynamic,
+            folded: HashMap::new(),
+        })
     }
 
-    pub fn resolve_vars(&self, str: &VarStr) -> String {
-        let re_var = regex!(r"\$\{([^}]+)\}");
-        let out = re_var
-            .replace_all(&str.0, |v: &regex::Captures| {
-                let key = v[1].to_string();
-                self.variables.get(&key).unwrap_or(&str.0).to_string()
+    /// Parses the makefile at `path`, with the variables in `env` exported
+    /// to it by the makefile calling it.
+    fn parse(
+        &self,
+        idgen: &mut IDGen,
+        exts: &mut HashSet<External<VarStr>>,
+        path: PathBuf,
+        env: &[(String, String)],
+    ) -> Result<Makefile, crate::Error> {
+        let path = match &self.files {
+            Some(_) => normalize(&path),
+            None => path,
+        };
+        let data = self.read(&path)?;
+        let path = match &self.files {
+            Some(_) => path,
+            None => path
+                .canonicalize()
+                .map_err(|source| Error::IOAt { path, source })?,
+        };
+        let mut terms = self.terms(&path, &data)?;
+        // Like the rest of the environment, these are defined before the
+        // first line and passed on in turn. Their values are already
+        // expanded, so they're escaped for the `:=`.
+        let env = env
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.replace('$', "$$")))
+            .collect::<Vec<(&str, String)>>();
+        let env = env.iter().map(|(name, value)| {
+            ast::Term::Variable(ast::Variable {
+                modifiers: vec!["export"],
+                name,
+                op: ":=",
+                value,
+            })
+        });
+        // Each make call starts its list with the makefile it reads, and
+        // adds included ones as they're read.
+        let makefile_list = path.display().to_string().replace('$', "$$");
+        let makefile_list = ast::Term::Variable(ast::Variable {
+            modifiers: Vec::new(),
+            name: "MAKEFILE_LIST",
+            op: ":=",
+            value: &makefile_list,
+        });
+        terms.splice(0..0, env.chain([makefile_list]));
+        Ok(Makefile::from_terms(idgen, exts, path, terms, self))
+    }
+
+    /// Reads the makefile at `path`, from the given files if there are any.
+    fn read(&self, path: &Path) -> Result<String, crate::Error> {
+        eprintln!("Parsing {}", path.display());
+        let io_err = |path: &Path| {
+            let path = path.to_path_buf();
+            move |source| Error::IOAt { path, source }
+        };
+        match &self.files {
+            Some(files) => files.get(path).cloned().ok_or_else(|| {
+                io_err(path)(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "not among the given files",
+                ))
+            }),
+            None => std::fs::read_to_string(path).map_err(io_err(path)),
+        }
+    }
+
+    /// Parses `data`, read from `path`, and applies the term transform.
+    fn terms<'a>(&self, path: &Path, data: &'a str) -> Result<Vec<ast::Term<'a>>, crate::Error> {
+        let terms = parser::Makefile::parse(data).map_err(|e| Error::from_nom(data, e))?;
+        Ok(match &self.term_transform {
+            Some(transform) => transform(path, terms),
+            None => terms,
+        })
+    }
+
+    fn resolve(&self, m: &Makefile, path: &VarStr) -> Result<PathBuf, crate::Error> {
+        let Some(files) = &self.files else {
+            return m.resolve_makefile(path, self.home.as_deref());
+        };
+        let dir = m.file.parent().unwrap_or(Path::new(""));
+        let path = normalize(&dir.join(expand_home(&m.resolve_vars(path), self.home.as_deref())));
+        if files.contains_key(&path) {
+            return Ok(path);
+        }
+        let makefile = path.join("Makefile");
+        match files.contains_key(&makefile) {
+            true => Ok(makefile),
+            false => Err(Error::PathErr(format!(
+                "No such makefile: {}",
+                path.display()
+            ))),
+        }
+    }
+}
+
+impl Makefile {
+    pub fn get_id(&self, name: &str) -> Option<&ID> {
+        self.tasks.id_of(name)
+    }
+    pub fn walk_from(path: impl AsRef<Path>) -> Result<DependencyGraph, crate::Error> {
+        Walker::new(path).walk()
+    }
+
+    pub fn walk_from_with_observer(
+        path: impl AsRef<Path>,
+        observer: impl FnMut(&External<PathBuf>),
+    ) -> Result<DependencyGraph, crate::Error> {
+        Walker::new(path).walk_with_observer(observer)
+    }
+
+    /// Parses a single makefile without following the makefiles it invokes.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, crate::Error> {
+        let path = path.as_ref().to_path_buf();
+        let walker = Walker::new(&path);
+        walker.parse(&mut IDGen::new("task"), &mut HashSet::new(), path, &[])
+    }
+
+    /// The task make builds when called without targets: the one named by
+    /// `.DEFAULT_GOAL`, or else the first target defined. Special targets
+    /// like `.PHONY` and pattern rules don't count.
+    pub fn default_goal(&self) -> Option<&ID> {
+        if let Some(goal) = self.variables.get(".DEFAULT_GOAL") {
+            let goal = self.resolve_vars(&VarStr(goal.value.clone()));
+            return self.get_id(goal.trim());
+        }
+        let special = |name: &str| name.starts_with('.') && !name.contains('/');
+        self.tasks
+            .iter()
+            .find(|(_, t)| !special(&t.name) && !t.name.contains('%'))
+            .map(|(id, _)| id)
+    }
+
+    /// Every variable with its raw and resolved value, sorted by name.
+    pub fn resolved_variables(&self) -> Vec<(&str, &str, String)> {
+        let mut vars = self
+            .variables
+            .iter()
+            .map(|(name, value)| {
+                // Through a reference, so each flavor expands its own way.
+                let resolved = self.resolve_vars(&VarStr(format!("$({})", name)));
+                (name.as_str(), value.value.as_str(), resolved)
+            })
+            .collect::<Vec<(&str, &str, String)>>();
+        vars.sort_by_key(|(name, _, _)| *name);
+        vars
+    }
+
+    /// Variables passed on to sub-makes, all of them after
+    /// `.EXPORT_ALL_VARIABLES`, with their values expanded.
+    pub fn exported_variables(&self) -> Vec<(String, String)> {
+        let mut vars = self
+            .variables
+            .keys()
+            .filter(|name| self.export_all || self.exported.contains(*name))
+            .map(|name| {
+                let value = self.resolve_vars(&VarStr(format!("$({})", name)));
+                (name.clone(), value)
+            })
+            .collect::<Vec<(String, String)>>();
+        vars.sort();
+        vars
+    }
+
+    /// Adds the variables and tasks of `other`, as if its contents followed
+    /// this makefile's. Its variables override ours, its tasks get new ids
+    /// from `id`, and targets phony in either makefile are phony in both.
+    pub fn merge(&mut self, id: &mut IDGen, other: Makefile) {
+        for name in other.variables.keys() {
+            self.shell_assigned.remove(name);
+        }
+        self.variables.extend(other.variables);
+        self.shell_assigned.extend(other.shell_assigned);
+        self.export_all |= other.export_all;
+        self.exported.extend(other.exported);
+        self.includes.extend(other.includes);
+        let phonies = other
+            .tasks
+            .values()
+            .filter(|t| t.phony)
+            .map(|t| t.name.clone())
+            .collect::<HashSet<String>>();
+        for task in other.tasks.into_values() {
+            self.tasks.insert(id.next(), task);
+        }
+        let phonies = self
+            .tasks
+            .values()
+            .filter(|t| t.phony)
+            .map(|t| t.name.clone())
+            .chain(phonies)
+            .collect::<HashSet<String>>();
+        for task in self.tasks.values_mut() {
+            task.phony = phonies.contains(&task.name);
+        }
+    }
+
+    /// Names of file targets whose prerequisites are all phony. Phony
+    /// targets are never up to date, so neither are these.
+    pub fn always_rebuilt(&self) -> Vec<&str> {
+        let mut names = self
+            .tasks
+            .values()
+            .filter(|t| !t.phony && !t.name.starts_with('.') && !t.dependencies.is_empty())
+            .filter(|t| {
+                t.dependencies
+                    .iter()
+                    .all(|d| self.get_id(d).is_some_and(|id| self.tasks[id].phony))
+            })
+            .map(|t| t.name.as_str())
+            .collect::<Vec<&str>>();
+        names.sort();
+        names
+    }
+
+    /// Compares declared phony targets with a guess based on names and
+    /// recipes. Returns the targets that look phony but aren't declared, and
+    /// the declared ones that look like they produce a file, both sorted.
+    pub fn lint_phony(&self) -> (Vec<&str>, Vec<&str>) {
+        let mut undeclared = Vec::new();
+        let mut misdeclared = Vec::new();
+        for task in self.tasks.values().filter(|t| !t.name.starts_with('.')) {
+            match (task.phony, task.looks_phony()) {
+                (false, true) => undeclared.push(task.name.as_str()),
+                (true, false) => misdeclared.push(task.name.as_str()),
+                _ => (),
+            }
+        }
+        undeclared.sort();
+        undeclared.dedup();
+        misdeclared.sort();
+        misdeclared.dedup();
+        (undeclared, misdeclared)
+    }
+
+    /// Terms or recipe lines of the branch `conditional` takes, or of every
+    /// branch when that can't be decided.
+    fn taken_branch<'a, T>(&self, conditional: ast::Conditional<'a, T>) -> Vec<T> {
+        let taken = match conditional.operands[..] {
+            [lhs, rhs] => self.eval_condition(conditional.kind, lhs, rhs),
+            [name] => self.eval_condition(conditional.kind, name, ""),
+            _ => None,
+        };
+        match taken {
+            Some(true) => conditional.then,
+            Some(false) => conditional.otherwise,
+            None => {
+                let mut terms = conditional.then;
+                terms.extend(conditional.otherwise);
+                terms
+            }
+        }
+    }
+
+    /// Commands of a recipe, in the branches its conditionals take.
+    fn recipe_commands(&self, recipe: Vec<ast::Recipe>) -> Vec<String> {
+        recipe
+            .into_iter()
+            .flat_map(|line| match line {
+                ast::Recipe::Command(cmd) => vec![cmd.to_string()],
+                ast::Recipe::Conditional(c) => self.recipe_commands(self.taken_branch(c)),
             })
-            .into_owned();
+            .collect()
+    }
+
+    /// Decides a conditional from the variables defined so far. `ifeq` and
+    /// `ifneq` compare the expanded operands, `ifdef` and `ifndef` check
+    /// whether the variable named by `lhs` has a value. Returns `None` when
+    /// that depends on anything the makefile doesn't determine, e.g. the
+    /// environment, the output of a shell command or a function that isn't
+    /// supported.
+    pub fn eval_condition(&self, kind: &str, lhs: &str, rhs: &str) -> Option<bool> {
+        match kind {
+            "ifeq" | "ifneq" => {
+                let equal = self.resolve_known(lhs)? == self.resolve_known(rhs)?;
+                Some(equal == (kind == "ifeq"))
+            }
+            "ifdef" | "ifndef" => {
+                let name = self.resolve_known(lhs)?;
+                if !self.variables.contains_key(&name) || self.shell_assigned.contains(&name) {
+                    return None;
+                }
+                let defined = !self.variables[&name].value.is_empty();
+                Some(defined == (kind == "ifdef"))
+            }
+            _ => None,
+        }
+    }
+
+    /// Groups of tasks with the exact same, non-empty, recipe. Each group is
+    /// sorted, and so are the groups.
+    pub fn duplicate_recipes(&self) -> Vec<Vec<&str>> {
+        let mut recipes: HashMap<String, Vec<&str>> = HashMap::new();
+        for task in self.tasks.values().filter(|t| !t.commands.is_empty()) {
+            recipes
+                .entry(task.commands.join("\n"))
+                .or_default()
+                .push(task.name.as_str());
+        }
+        let mut groups = recipes
+            .into_values()
+            .filter(|names| names.len() > 1)
+            .map(|mut names| {
+                names.sort();
+                names
+            })
+            .collect::<Vec<Vec<&str>>>();
+        groups.sort();
+        groups
+    }
+
+    /// Prerequisites whose recipe mentions an earlier sibling prerequisite
+    /// they don't depend on, so they only work when built left to right,
+    /// which `make -j` doesn't do. Returns (target, prerequisite, sibling)
+    /// triples, sorted.
+    pub fn ordering_assumptions(&self) -> Vec<(&str, &str, &str)> {
+        let mut found = Vec::new();
+        for task in self.tasks.values() {
+            for (i, dep) in task.dependencies.iter().enumerate() {
+                let Some(id) = self.get_id(dep) else {
+                    continue;
+                };
+                let words = self.tasks[id]
+                    .commands
+                    .iter()
+                    .flat_map(|c| c.split(|c: char| c.is_whitespace() || "\"';|&<>()".contains(c)))
+                    .collect::<HashSet<&str>>();
+                for sibling in task.dependencies[..i].iter() {
+                    if sibling != dep
+                        && words.contains(sibling.as_str())
+                        && !self.depends_on(dep, sibling)
+                    {
+                        found.push((task.name.as_str(), dep.as_str(), sibling.as_str()));
+                    }
+                }
+            }
+        }
+        found.sort();
+        found.dedup();
+        found
+    }
+
+    /// Ids of the tasks `id` depends on, in order.
+    pub(crate) fn dependency_ids(&self, id: &ID) -> Vec<&ID> {
+        self.tasks[id]
+            .dependencies
+            .iter()
+            .filter_map(|d| self.get_id(d))
+            .collect()
+    }
+
+    /// Dependency cycles, each as the ids of the tasks on it in order. The
+    /// last task depends on the first.
+    pub fn find_cycles(&self) -> Vec<Vec<ID>> {
+        crate::graph::find_cycles(self.tasks.keys(), |id| self.dependency_ids(id))
+    }
+
+    /// Task ids with every task after the ones it depends on, and otherwise
+    /// in definition order. Fails with a cycle when there is one.
+    pub fn topo_order(&self) -> Result<Vec<ID>, Cycle> {
+        if let Some(cycle) = self.find_cycles().into_iter().next() {
+            return Err(Cycle(cycle));
+        }
+        let mut order = Vec::new();
+        let mut seen = HashSet::new();
+        for start in self.tasks.keys() {
+            // Tasks are visited twice, the second time once their
+            // dependencies are in the order.
+            let mut stack = vec![(start, false)];
+            while let Some((id, deps_done)) = stack.pop() {
+                if deps_done {
+                    order.push(id.clone());
+                    continue;
+                }
+                if !seen.insert(id) {
+                    continue;
+                }
+                stack.push((id, true));
+                for dep in self.dependency_ids(id).into_iter().rev() {
+                    stack.push((dep, false));
+                }
+            }
+        }
+        Ok(order)
+    }
+
+    /// Ids of the task named `name` and every task it needs, through
+    /// dependencies and order-only prerequisites. Empty when there's no such
+    /// task.
+    pub fn reachable_from(&self, name: &str) -> HashSet<ID> {
+        let Some(start) = self.get_id(name) else {
+            return HashSet::new();
+        };
+        let mut reachable = HashSet::from([start.clone()]);
+        let mut queue = VecDeque::from([start]);
+        while let Some(id) = queue.pop_front() {
+            let task = &self.tasks[id];
+            let deps = task.dependencies.iter().chain(task.order_only.iter());
+            for dep in deps.filter_map(|d| self.get_id(d)) {
+                if reachable.insert(dep.clone()) {
+                    queue.push_back(dep);
+                }
+            }
+        }
+        reachable
+    }
+
+    /// Whether the task named `from` depends on `to`, directly or not.
+    fn depends_on(&self, from: &str, to: &str) -> bool {
+        let mut seen = HashSet::new();
+        let mut stack = vec![from];
+        while let Some(name) = stack.pop() {
+            if !seen.insert(name) {
+                continue;
+            }
+            let Some(id) = self.get_id(name) else {
+                continue;
+            };
+            for dep in self.tasks[id].dependencies.iter() {
+                if dep == to {
+                    return true;
+                }
+                stack.push(dep);
+            }
+        }
+        false
+    }
+
+    /// Expands the variable references and functions in `str` with the
+    /// variables of this makefile.
+    ///
+    /// ```
+    /// use std::{collections::HashSet, path::PathBuf};
+    ///
+    /// use makedot::{
+    ///     ast::Parse as _,
+    ///     makefile::{IDGen, Makefile, VarStr, Walker},
+    ///     parser,
+    /// };
+    ///
+    /// let terms = parser::Makefile::parse("CC = gcc\nFLAGS = -O2\n").unwrap();
+    /// let m = Makefile::from_terms(
+    ///     &mut IDGen::new("task"),
+    ///     &mut HashSet::new(),
+    ///     PathBuf::from("Makefile"),
+    ///     terms,
+    ///     &Walker::default(),
+    /// );
+    /// assert_eq!(m.resolve_vars(&VarStr::from("${CC} $(FLAGS)")), "gcc -O2");
+    /// ```
+    pub fn resolve_vars(&self, str: &VarStr) -> String {
+        let mut expander = Expander::new(self);
+        let out = expander.expand(&str.0);
+        self.warn(expander.warnings);
         out
     }
-    pub fn resolve_makefile(&self, path: &VarStr) -> Result<PathBuf, crate::Error> {
+
+    /// Prints each of `warnings` the first time it comes up.
+    fn warn(&self, warnings: Vec<String>) {
+        let mut warned = self.warned.borrow_mut();
+        for warning in warnings {
+            if !warned.contains(&warning) {
+                eprintln!("{}: {}", self.file.display(), warning);
+                warned.insert(warning);
+            }
+        }
+    }
+
+    /// Like [`Makefile::resolve_vars`], but `None` when any reference can't
+    /// be resolved from the makefile alone.
+    fn resolve_known(&self, str: &str) -> Option<String> {
+        let mut expander = Expander::new(self);
+        let out = expander.expand(str);
+        self.warn(expander.warnings);
+        expander.unresolved.is_empty().then_some(out)
+    }
+
+    pub fn resolve_makefile(
+        &self,
+        path: &VarStr,
+        home: Option<&Path>,
+    ) -> Result<PathBuf, crate::Error> {
         let path = self
             .file
             .parent()
@@ -131,7 +1115,7 @@ impl Makefile {
                 "Makefile path has no parent: {}",
                 self.file.display()
             )))?
-            .join(self.resolve_vars(path));
+            .join(expand_home(&self.resolve_vars(path), home));
         let mut path = path.canonicalize().map_err(|err| {
             Error::PathErr(format!(
                 "Couldn't canonicalize {},\n{}",
@@ -145,84 +1129,1973 @@ impl Makefile {
 
         Ok(path)
     }
+
+    /// Reads the terms parsed from the makefile at `path`, with the settings
+    /// of `walker`. Make calls in recipes are added to `external`.
     pub fn from_terms(
         id: &mut IDGen,
         external: &mut HashSet<External<VarStr>>,
         path: PathBuf,
         terms: Vec<ast::Term>,
+        walker: &Walker,
     ) -> Self {
-        let path = path.canonicalize().expect("Invalid makefile path");
         let mut out = Self {
-            file: path,
+            file: path.clone(),
             variables: Variables::new(),
-            tasks: HashMap::new(),
+            tasks: Tasks::new(),
+            export_all: false,
+            exported: HashSet::new(),
+            shell_assigned: HashSet::new(),
+            includes: Vec::new(),
+            include_cycles: Vec::new(),
+            env: walker.env.clone(),
+            warned: RefCell::default(),
+        };
+        let mut reader = Reader {
+            id,
+            external,
+            walker,
+            local_vars: HashMap::new(),
+            files: vec![path],
         };
+        out.read_terms(&mut reader, terms);
 
-        let phonies = terms
-            .iter()
-            .filter_map(|t| match t {
-                ast::Term::Task(t) if t.name == ".PHONY" => Some(t.dependencies.clone()),
-                _ => None,
-            })
-            .flatten()
-            .collect::<Vec<&str>>();
+        let phonies = out
+            .tasks
+            .values()
+            .filter(|t| t.name == ".PHONY")
+            .flat_map(|t| t.dependencies.clone())
+            .collect::<HashSet<String>>();
+        for task in out.tasks.values_mut() {
+            task.phony = phonies.contains(&task.name);
+            if let Some(vars) = reader.local_vars.get(&task.name) {
+                task.local_vars = vars.clone();
+            }
+        }
+
+        out
+    }
 
-        for term in terms {
+    /// Reads `terms` into the makefile, from the last of `reader.files`.
+    fn read_terms(&mut self, reader: &mut Reader, terms: Vec<ast::Term>) {
+        let file = reader.files.last().expect("Reading a file").clone();
+        for name in overridden_recipes(&terms) {
+            eprintln!("{}: overriding recipe for target {}", file.display(), name);
+        }
+
+        // Conditionals are replaced by the terms of the branch they take, in
+        // place, so those see exactly the variables defined before them.
+        let mut terms = VecDeque::from(terms);
+        while let Some(term) = terms.pop_front() {
             match term {
+                ast::Term::Conditional(c) => {
+                    for term in self.taken_branch(c).into_iter().rev() {
+                        terms.push_front(term);
+                    }
+                }
+                // A setting rather than a target.
+                ast::Term::Task(t) if t.names == [".EXPORT_ALL_VARIABLES"] => {
+                    self.export_all = true;
+                }
+                // Not a rule, so it doesn't add a task.
+                ast::Term::Task(t) if !t.local_vars.is_empty() => {
+                    for name in t.names {
+                        let name = self.resolve_vars(&VarStr(name.to_string()));
+                        for name in name.split_whitespace() {
+                            let vars = reader.local_vars.entry(name.to_string()).or_default();
+                            vars.extend(t.local_vars.iter().map(|v| {
+                                (v.name.to_string(), v.op.to_string(), v.value.to_string())
+                            }));
+                        }
+                    }
+                }
                 ast::Term::Task(t) => {
-                    let id = id.next();
-                    let dependencies = t.dependencies.into_iter().map(|v| v.to_string()).collect();
-                    let commands = t
-                        .commands
-                        .into_iter()
-                        .map(|c| c.to_string())
-                        .collect::<Vec<String>>();
-
-                    external.extend(commands.iter().filter_map(|c| out.parse_make_line(c)).map(
-                        |(path, tasks)| External {
-                            path: VarStr(path),
-                            id: id.clone(),
-                            tasks,
-                        },
-                    ));
-
-                    out.tasks.insert(
-                        id,
-                        Task {
-                            phony: phonies.contains(&t.name),
-                            name: t.name.to_string(),
-                            dependencies,
-                            commands,
-                        },
-                    );
-                }
-                ast::Term::Variable(v) => {
-                    out.variables
-                        .insert(v.name.to_string(), v.value.to_string());
+                    // Rule heads are expanded as they're read, with the
+                    // variables defined so far.
+                    let resolve = |names: Vec<&str>| {
+                        names
+                            .into_iter()
+                            .map(|v| self.resolve_vars(&VarStr(v.to_string())))
+                            .flat_map(|v| {
+                                v.split_whitespace()
+                                    .map(|v| v.to_string())
+                                    .collect::<Vec<String>>()
+                            })
+                            .collect::<Vec<String>>()
+                    };
+                    let targets = resolve(t.names);
+                    let dependencies = resolve(t.dependencies);
+                    let order_only = resolve(t.order_only);
+                    let commands = self.recipe_commands(t.commands);
+                    // Expanded first, so calls in canned recipes are found too.
+                    let calls = commands
+                        .iter()
+                        .flat_map(|c| self.parse_make_line(&self.expand_recipe_line(c)))
+                        .collect::<Vec<(String, Vec<String>)>>();
+
+                    // A static pattern rule is a rule for each listed target,
+                    // with its stem put into the prerequisite patterns.
+                    let rules = match t.target_pattern {
+                        Some(pattern) => {
+                            let pattern = self.resolve_vars(&VarStr(pattern.to_string()));
+                            targets
+                                .iter()
+                                .map(|target| {
+                                    let stem = pattern_stem(&pattern, target);
+                                    if stem.is_none() {
+                                        eprintln!(
+                                            "{}: target {} doesn't match the pattern {}",
+                                            file.display(),
+                                            target,
+                                            pattern
+                                        );
+                                    }
+                                    let substitute = |names: &[String]| {
+                                        names
+                                            .iter()
+                                            .filter_map(|d| match (d.contains('%'), stem) {
+                                                (false, _) => Some(d.clone()),
+                                                (true, Some(stem)) => {
+                                                    Some(d.replacen('%', stem, 1))
+                                                }
+                                                (true, None) => None,
+                                            })
+                                            .collect::<Vec<String>>()
+                                    };
+                                    (
+                                        target.to_string(),
+                                        substitute(&dependencies),
+                                        substitute(&order_only),
+                                    )
+                                })
+                                .collect::<Vec<(String, Vec<String>, Vec<String>)>>()
+                        }
+                        // Like several rules, one per target.
+                        None => targets
+                            .into_iter()
+                            .map(|target| (target, dependencies.clone(), order_only.clone()))
+                            .collect(),
+                    };
+
+                    for (name, dependencies, order_only) in rules {
+                        // Each double-colon rule runs its own recipe, so it stays
+                        // a separate node even when merging duplicates.
+                        let existing = match reader.walker.merge_duplicates && !t.double_colon {
+                            true => self.get_id(&name).cloned(),
+                            false => None,
+                        };
+                        let id = existing.clone().unwrap_or_else(|| reader.id.next());
+
+                        reader
+                            .external
+                            .extend(calls.iter().cloned().map(|(path, tasks)| External {
+                                path: VarStr(path),
+                                id: id.clone(),
+                                tasks,
+                            }));
+
+                        // Like make, later rules add prerequisites and override the recipe.
+                        if let Some(task) = existing.and_then(|id| self.tasks.get_mut(&id)) {
+                            for dep in dependencies {
+                                if !task.dependencies.contains(&dep) {
+                                    task.dependencies.push(dep);
+                                }
+                            }
+                            for dep in order_only {
+                                if !task.order_only.contains(&dep) {
+                                    task.order_only.push(dep);
+                                }
+                            }
+                            if !commands.is_empty() {
+                                task.commands = commands.clone();
+                            }
+                            continue;
+                        }
+
+                        self.tasks.insert(
+                            id,
+                            Task {
+                                phony: false,
+                                double_colon: t.double_colon,
+                                name,
+                                line: t.line,
+                                origin_file: file.clone(),
+                                dependencies,
+                                order_only,
+                                commands: commands.clone(),
+                                local_vars: Vec::new(),
+                            },
+                        );
+                    }
+                }
+                ast::Term::Variable(mut v) if !v.modifiers.is_empty() => {
+                    for modifier in std::mem::take(&mut v.modifiers) {
+                        match modifier {
+                            "export" => {
+                                self.exported.insert(v.name.to_string());
+                            }
+                            "unexport" => {
+                                self.exported.remove(v.name);
+                            }
+                            _ => (),
+                        }
+                    }
+                    terms.push_front(ast::Term::Variable(v));
+                }
+                ast::Term::Export(e) if e.names.is_empty() => {
+                    self.export_all = e.export;
+                }
+                ast::Term::Export(e) => {
+                    for name in e.names {
+                        let name = self.resolve_vars(&VarStr(name.to_string()));
+                        for name in name.split_whitespace() {
+                            if e.export {
+                                self.exported.insert(name.to_string());
+                            } else {
+                                self.exported.remove(name);
+                            }
+                        }
+                    }
+                }
+                ast::Term::Variable(ast::Variable {
+                    name, op, value, ..
+                })
+                | ast::Term::Define {
+                    name,
+                    op,
+                    body: value,
+                } => {
+                    // Expanded before the old value is replaced, which
+                    // `A := $(A) x` still refers to.
+                    let expand = |value: &str| self.resolve_vars(&VarStr::from(value));
+                    let value = match (op, self.variables.get(name)) {
+                        ("?=", Some(old)) => old.clone(),
+                        ("+=", Some(old)) => {
+                            let value = match old.is_immediate() {
+                                true => expand(value),
+                                false => value.to_string(),
+                            };
+                            match old.value.is_empty() {
+                                true => Value::new(&old.op, value),
+                                false => Value::new(&old.op, format!("{} {}", old.value, value)),
+                            }
+                        }
+                        ("+=" | "?=", None) => Value::new("=", value),
+                        (":=" | "::=", _) => Value::new(op, expand(value)),
+                        _ => Value::new(op, value),
+                    };
+                    match value.op == "!=" {
+                        true => self.shell_assigned.insert(name.to_string()),
+                        false => self.shell_assigned.remove(name),
+                    };
+                    self.variables.insert(name.to_string(), value);
+                }
+                ast::Term::Include(include) => {
+                    for paths in include.files {
+                        let paths = self.resolve_vars(&VarStr(paths.to_string()));
+                        for path in paths.split_whitespace() {
+                            let include = Include {
+                                path: path.to_string(),
+                                optional: include.optional,
+                            };
+                            self.includes.push(include.clone());
+                            // In place, like make, so the included file sees
+                            // the variables defined before the `include` and
+                            // the lines after it see the ones it defines.
+                            if reader.walker.merge_includes {
+                                self.read_include(reader, &include);
+                            }
+                        }
+                    }
                 }
                 ast::Term::Empty | ast::Term::Unimplemented(_) => (),
             }
         }
-
-        out
     }
 
-    fn parse_make_line(&self, line: &str) -> Option<(String, Vec<String>)> {
-        let re_cmd = regex!(r"make (((\\\n)|([^\n#|&>]))+)\n?");
-        let re_positionals = regex!(r"\s(\w[^=:\s/]+)(\s|$)");
-        let re_path = regex!(r"(-C ?([^\s]+)|-f ?(((\\ )|[^\s])+))");
-        let cmd = re_cmd.captures(line)?;
-        let args = &cmd[1];
-        let tasks = re_positionals
-            .captures_iter(args)
-            .map(|c| c[1].to_string())
+    /// Reports a chain of includes leading back to its first file.
+    fn include_cycle(&mut self, cycle: Vec<PathBuf>) {
+        let files = cycle
+            .iter()
+            .map(|f| f.display().to_string())
             .collect::<Vec<String>>();
-        let path = {
-            let c = re_path.captures(args)?;
-            let path = c.get(2).or_else(|| c.get(3))?;
-            path.as_str().to_string()
+        eprintln!(
+            "{}: include cycle: {}",
+            self.file.display(),
+            files.join(" -> ")
+        );
+        self.include_cycles.push(cycle);
+    }
+
+    /// Reads the makefile `include` names, as if its lines replaced the
+    /// `include`.
+    fn read_include(&mut self, reader: &mut Reader, include: &Include) {
+        let path = match reader.walker.resolve(self, &VarStr(include.path.clone())) {
+            Ok(path) => path,
+            Err(_) if include.optional => return,
+            Err(err) => {
+                eprintln!("Couldn't resolve include: {}, {}", include.path, err);
+                return;
+            }
+        };
+        // Still being read, so reading it again would never end.
+        if let Some(i) = reader.files.iter().position(|f| *f == path) {
+            let mut cycle = reader.files[i..].to_vec();
+            cycle.push(path);
+            self.include_cycle(cycle);
+            return;
+        }
+        let data = match reader.walker.read(&path) {
+            Ok(data) => data,
+            Err(err) => {
+                eprintln!("Couldn't read include: {}", err);
+                return;
+            }
+        };
+        let terms = match reader.walker.terms(&path, &data) {
+            Ok(terms) => terms,
+            Err(err) => {
+                eprintln!("Couldn't parse include: {}, {}", path.display(), err);
+                return;
+            }
+        };
+        // Like make, which lists each makefile as it starts reading it.
+        let list = self
+            .variables
+            .entry("MAKEFILE_LIST".to_string())
+            .or_insert_with(|| Value::new(":=", ""));
+        if !list.value.is_empty() {
+            list.value.push(' ');
+        }
+        list.value.push_str(&path.display().to_string());
+        reader.files.push(path);
+        self.read_terms(reader, terms);
+        reader.files.pop();
+    }
+
+    /// Expands a recipe line like make does before running it, with `$(MAKE)`
+    /// standing for make itself unless the makefile sets it. Dollars are
+    /// escaped again, so paths taken from the line resolve to themselves.
+    fn expand_recipe_line(&self, line: &str) -> String {
+        let mut expander = Expander::new(self);
+        if !self.variables.contains_key("MAKE") {
+            expander.bind("MAKE", "make");
+        }
+        let out = expander.expand(line);
+        self.warn(expander.warnings);
+        out.replace('$', "$$")
+    }
+
+    /// Every make call in a recipe line, as the makefile's path and the
+    /// targets. Commands chained with `&&`, `||`, `;` or pipes each get
+    /// their own call.
+    fn parse_make_line(&self, line: &str) -> Vec<(String, Vec<String>)> {
+        let line = line.replace("\\\n", " ");
+        // The rest of the line is a shell comment.
+        let line = line.split('#').next().unwrap_or_default();
+        line.split([';', '&', '|', '\n'])
+            .filter_map(|cmd| self.parse_make_call(cmd))
+            .collect()
+    }
+
+    fn parse_make_call(&self, line: &str) -> Option<(String, Vec<String>)> {
+        // The program starts a word, so e.g. `cmake` isn't taken for make.
+        let re_cmd = regex!(
+            r"(^|[\s;&|(@+/-])(make|\$\(MAKE\)|\$\{MAKE\}) (?P<args>((\\\n)|([^\n#|&>;]))+)\n?"
+        );
+        let re_arg = regex!(r"((\\.)|[^\s\\])+");
+        let cmd = re_cmd.captures(line).or_else(|| {
+            // MAKE may be set to another program, called directly.
+            let program = self.variables.get("MAKE")?;
+            let program = self.resolve_vars(&VarStr(program.value.clone()));
+            let program = program.trim();
+            if program.is_empty() {
+                return None;
+            }
+            let re_custom = regex::Regex::new(&format!(
+                r"(^|[\s;&|(@+/-]){} (?P<args>((\\\n)|([^\n#|&>;]))+)\n?",
+                regex::escape(program)
+            ))
+            .ok()?;
+            re_custom.captures(line)
+        })?;
+        let args = cmd["args"].replace("\\\n", " ");
+        let mut args = re_arg.find_iter(&args).map(|m| m.as_str()).peekable();
+        let (mut dir, mut file, mut tasks) = (None, None, Vec::new());
+        while let Some(arg) = args.next() {
+            match arg {
+                "-C" | "--directory" => dir = args.next(),
+                "-f" | "--file" | "--makefile" => file = args.next(),
+                // Options taking a separate argument that isn't a target.
+                "-I" | "--include-dir" | "-o" | "--old-file" | "-W" | "--what-if" => {
+                    args.next();
+                }
+                // Their argument is optional, but never a target when numeric.
+                "-j" | "--jobs" | "-l" | "--load-average" => {
+                    args.next_if(|a| a.parse::<f64>().is_ok());
+                }
+                _ if arg.starts_with("--directory=") => dir = arg.split_once('=').map(|(_, v)| v),
+                _ if arg.starts_with("--file=") || arg.starts_with("--makefile=") => {
+                    file = arg.split_once('=').map(|(_, v)| v)
+                }
+                _ if arg.starts_with("-C") => dir = Some(&arg[2..]),
+                _ if arg.starts_with("-f") => file = Some(&arg[2..]),
+                _ if arg.starts_with('-') => (),
+                // Variable overrides, e.g. `make CC=clang`.
+                _ if arg.contains('=') => (),
+                _ => tasks.push(arg.to_string()),
+            }
+        }
+        let path = match (dir, file) {
+            (Some(dir), Some(file)) => format!("{}/{}", dir, file),
+            (Some(path), None) | (None, Some(path)) => path.to_string(),
+            (None, None) => return None,
         };
         eprintln!("Parsed {:?} {:?}", path, tasks);
         Some((path, tasks))
     }
 }
+
+/// Reads makefile paths from a response file, one per line. Blank lines and
+/// lines starting with `#` are skipped, relative paths are relative to the
+/// response file.
+pub fn read_response_file(path: &Path) -> Result<Vec<PathBuf>, crate::Error> {
+    let data = std::fs::read_to_string(path).map_err(|source| Error::IOAt {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let dir = path.parent().unwrap_or(Path::new(""));
+    Ok(data
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(|l| dir.join(l))
+        .collect())
+}
+
+/// The part of `target` matching the `%` in `pattern`, if it matches.
+fn pattern_stem<'a>(pattern: &str, target: &'a str) -> Option<&'a str> {
+    let (prefix, suffix) = pattern.split_once('%')?;
+    target
+        .strip_prefix(prefix)?
+        .strip_suffix(suffix)
+        .filter(|stem| !stem.is_empty())
+}
+
+/// Resolves `.` and `..` components without looking at the disk, so
+/// symlinks aren't followed.
+fn normalize(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for component in path.components() {
+        match (component, out.components().next_back()) {
+            (Component::CurDir, _) => (),
+            (Component::ParentDir, Some(Component::Normal(_))) => {
+                out.pop();
+            }
+            // Nothing is above the root.
+            (Component::ParentDir, Some(Component::RootDir | Component::Prefix(_))) => (),
+            (component, _) => out.push(component),
+        }
+    }
+    out
+}
+
+/// Replaces a leading `~` or `~user` with the matching home directory. Other
+/// users' homes are assumed to sit next to `home`.
+fn expand_home(path: &str, home: Option<&Path>) -> PathBuf {
+    let (Some(rest), Some(home)) = (path.strip_prefix('~'), home) else {
+        return PathBuf::from(path);
+    };
+    let (user, rest) = rest.split_once('/').unwrap_or((rest, ""));
+    let home = match (user, home.parent()) {
+        ("", _) => home.to_path_buf(),
+        (user, Some(homes)) => homes.join(user),
+        (_, None) => return PathBuf::from(path),
+    };
+    home.join(rest)
+}
+
+/// Names of targets given a recipe by more than one rule. make warns about
+/// these and only keeps the last recipe.
+fn overridden_recipes<'a>(terms: &[ast::Term<'a>]) -> Vec<&'a str> {
+    let mut with_recipe = HashSet::new();
+    let mut overridden = Vec::new();
+    let mut stack = terms.iter().rev().collect::<Vec<&ast::Term>>();
+    while let Some(term) = stack.pop() {
+        match term {
+            ast::Term::Task(t) if !t.double_colon && !t.commands.is_empty() => {
+                for name in t.names.iter() {
+                    if !with_recipe.insert(*name) && !overridden.contains(name) {
+                        overridden.push(name);
+                    }
+                }
+            }
+            // Branches aren't known yet, so all of them count.
+            ast::Term::Conditional(c) => {
+                stack.extend(c.then.iter().chain(c.otherwise.iter()).rev());
+            }
+            _ => (),
+        }
+    }
+    overridden
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::{HashMap, HashSet},
+        path::{Path, PathBuf},
+    };
+
+    use super::{IDGen, Makefile, Value, VarStr, Walker, ID};
+    use crate::{
+        ast::{Parse as _, Task, Term, Variable},
+        parser,
+        testing::fixture,
+        Error,
+    };
+
+    #[test]
+    fn test_makefile_list() {
+        let own_dir = "SELF := $(dir $(lastword $(MAKEFILE_LIST)))\n";
+        let root = fixture(
+            "makefile_list",
+            &[
+                ("Makefile", &format!("{own_dir}all:\n\tmake -C sub build\n")),
+                (
+                    "sub/Makefile",
+                    &format!("{own_dir}include $(SELF)rules.mk\nbuild:\n"),
+                ),
+                ("sub/rules.mk", "RULES := $(lastword $(MAKEFILE_LIST))\n"),
+            ],
+        );
+        let makefiles = Walker::new(root.join("Makefile"))
+            .merge_includes(true)
+            .walk()
+            .unwrap()
+            .makefiles;
+
+        assert_eq!(makefiles.len(), 2);
+        for m in makefiles.iter() {
+            let dir = m.file.parent().unwrap().display().to_string();
+            assert_eq!(m.variables["SELF"].value, dir + "/");
+        }
+        // Each make call has a list of its own, with includes added as
+        // they're read.
+        let sub = &makefiles[1];
+        let rules = sub.file.with_file_name("rules.mk").display().to_string();
+        assert_eq!(sub.variables["RULES"].value, rules);
+        assert_eq!(
+            sub.variables["MAKEFILE_LIST"].value,
+            format!("{} {}", sub.file.display(), rules)
+        );
+    }
+
+    #[test]
+    fn test_term_transform() {
+        let root = fixture("term_transform", &[("Makefile", "all:\n")]);
+        let graph = Walker::new(root.join("Makefile"))
+            .term_transform(|_, mut terms| {
+                terms.push(Term::Task(Task {
+                    names: vec!["synthetic"],
+                    double_colon: false,
+                    target_pattern: None,
+                    dependencies: vec!["all"],
+                    order_only: vec![],
+                    commands: vec![],
+                    local_vars: vec![],
+                    line: 0,
+                }));
+                terms
+            })
+            .walk()
+            .unwrap();
+
+        let m = &graph.makefiles[0];
+        let id = m.get_id("synthetic").expect("synthetic task missing");
+        assert_eq!(m.tasks[id].dependencies, vec!["all"]);
+    }
+
+    #[test]
+    fn test_computed_name() {
+        let root = fixture(
+            "computed_name",
+            &[("Makefile", "ARCH = x86\nCC_x86 = gcc\nall: $(CC_$(ARCH))\n")],
+        );
+        let m = &Makefile::walk_from(root.join("Makefile"))
+            .unwrap()
+            .makefiles[0];
+        let dep = &m.tasks[m.get_id("all").unwrap()].dependencies[0];
+
+        assert_eq!(dep, "gcc");
+    }
+
+    #[test]
+    fn test_io_error_path() {
+        let root = fixture("io_error_path", &[("dir/Makefile", "all:\n")]);
+        for path in [root.join("missing"), root.join("dir")] {
+            match Makefile::walk_from(&path) {
+                Err(err @ Error::IOAt { .. }) => {
+                    let msg = err.to_string();
+                    assert!(msg.contains(&path.display().to_string()), "{}", msg);
+                }
+                res => panic!("expected IOAt error for {:?}, got {:?}", path, res),
+            }
+        }
+    }
+
+    #[test]
+    fn test_response_file() {
+        let root = fixture(
+            "response_file",
+            &[
+                ("roots.txt", "# generated\na/Makefile\n\n  b/Makefile\n"),
+                ("a/Makefile", "liba:\n"),
+                ("b/Makefile", "libb:\n"),
+            ],
+        );
+        let roots = super::read_response_file(&root.join("roots.txt")).unwrap();
+        assert_eq!(
+            roots,
+            vec![root.join("a/Makefile"), root.join("b/Makefile")]
+        );
+
+        let walker = roots[1..]
+            .iter()
+            .fold(Walker::new(&roots[0]), |w, root| w.add_root(root));
+        let graph = walker.walk().unwrap();
+        assert_eq!(graph.makefiles.len(), 2);
+        assert!(graph.find_task("liba").is_some());
+        assert!(graph.find_task("libb").is_some());
+    }
+
+    #[test]
+    fn test_make_line() {
+        let m = Makefile::open(fixture("make_line", &[("Makefile", "")]).join("Makefile")).unwrap();
+        let cases = [
+            ("make -C lib a b", vec![("lib", vec!["a", "b"])]),
+            ("make -Clib -j 4 CC=clang all", vec![("lib", vec!["all"])]),
+            (
+                "make -f sub/Makefile -s install",
+                vec![("sub/Makefile", vec!["install"])],
+            ),
+            (
+                "make --directory=lib -f lib.mk x",
+                vec![("lib/lib.mk", vec!["x"])],
+            ),
+            ("make -f my\\ file.mk -j", vec![("my\\ file.mk", vec![])]),
+            (
+                "make -C lib a; make -C doc b",
+                vec![("lib", vec!["a"]), ("doc", vec!["b"])],
+            ),
+            (
+                "make -C a x && make -C b || make -C c\\\n y",
+                vec![("a", vec!["x"]), ("b", vec![]), ("c", vec!["y"])],
+            ),
+            ("make -C a | tee log # make -C b", vec![("a", vec![])]),
+            ("echo make -C a > log", vec![("a", vec![])]),
+            ("make all", vec![]),
+            ("make -C $$HOME/lib x", vec![("$$HOME/lib", vec!["x"])]),
+            ("$(MAKE) -C lib all", vec![("lib", vec!["all"])]),
+            ("${MAKE} -C lib all", vec![("lib", vec!["all"])]),
+            ("@$(MAKE) -f lib.mk", vec![("lib.mk", vec![])]),
+            ("cd x && /usr/bin/make -C lib", vec![("lib", vec![])]),
+            ("cmake -C build all", vec![]),
+            ("gmake -C lib all", vec![]),
+        ];
+        for (i, (line, expected)) in cases.into_iter().enumerate() {
+            let expected = expected
+                .into_iter()
+                .map(|(path, tasks)| {
+                    (
+                        path.to_string(),
+                        tasks.into_iter().map(String::from).collect::<Vec<_>>(),
+                    )
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(
+                m.parse_make_line(line),
+                expected,
+                "case {:02}, line: {:?}",
+                i,
+                line
+            );
+        }
+
+        let m = Makefile::from_terms(
+            &mut IDGen::new("task"),
+            &mut HashSet::new(),
+            PathBuf::from("Makefile"),
+            parser::Makefile::parse("GNU = g\nMAKE = $(GNU)make\n").unwrap(),
+            &Walker::default(),
+        );
+        let expected = vec![("lib".to_string(), vec!["all".to_string()])];
+        assert_eq!(m.parse_make_line("gmake -C lib all"), expected);
+        assert_eq!(m.parse_make_line("$(MAKE) -C lib all"), expected);
+        assert_eq!(m.parse_make_line("cmake -C lib all"), vec![]);
+    }
+
+    #[test]
+    fn test_chained_make_calls() {
+        let root = fixture(
+            "chained_make_calls",
+            &[
+                ("Makefile", "all:\n\tmake -C a x && $(MAKE) -C b y\n"),
+                ("a/Makefile", "x:\n"),
+                ("b/Makefile", "y:\n"),
+            ],
+        );
+        let graph = Makefile::walk_from(root.join("Makefile")).unwrap();
+        let all = graph.find_task("all").unwrap();
+        let mut externals = graph
+            .externals
+            .iter()
+            .map(|e| (&e.id, e.path.strip_prefix(&root).unwrap(), e.tasks.clone()))
+            .collect::<Vec<_>>();
+        externals.sort();
+        assert_eq!(
+            externals,
+            vec![
+                (all, Path::new("a/Makefile"), vec!["x".to_string()]),
+                (all, Path::new("b/Makefile"), vec!["y".to_string()]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tasks_order() {
+        let mut m = Makefile::from_terms(
+            &mut IDGen::new("task"),
+            &mut HashSet::new(),
+            PathBuf::from("Makefile"),
+            parser::Makefile::parse("c:\na:\nd:\nb:\n").unwrap(),
+            &Walker::default(),
+        );
+        let names = |m: &Makefile| m.tasks.values().map(|t| t.name.clone()).collect::<Vec<_>>();
+        assert_eq!(names(&m), ["c", "a", "d", "b"]);
+
+        let id = m.get_id("a").unwrap().clone();
+        let a = m.tasks.remove(&id).unwrap();
+        m.tasks.retain(|_, t| t.name != "d");
+        assert_eq!(names(&m), ["c", "b"]);
+        assert!(m.tasks.get(m.get_id("b").unwrap()).is_some());
+
+        m.tasks.insert(id.clone(), a);
+        assert_eq!(names(&m), ["c", "b", "a"]);
+        assert_eq!(m.tasks[&id].name, "a");
+    }
+
+    #[test]
+    fn test_get_id() {
+        // Every name is defined twice, each rule getting its own task.
+        let data = (0..500)
+            .map(|i| format!("t{}: t{}\n", i % 250, (i + 1) % 250))
+            .collect::<String>();
+        let mut m = Makefile::from_terms(
+            &mut IDGen::new("task"),
+            &mut HashSet::new(),
+            PathBuf::from("Makefile"),
+            parser::Makefile::parse(&data).unwrap(),
+            &Walker::default(),
+        );
+        assert_eq!(m.tasks.len(), 500);
+        let linear = |m: &Makefile, name: &str| {
+            m.tasks
+                .iter()
+                .filter(|(_, t)| t.name == name)
+                .last()
+                .map(|(id, _)| id.clone())
+        };
+        let check = |m: &Makefile| {
+            for i in 0..260 {
+                let name = format!("t{}", i);
+                assert_eq!(m.get_id(&name).cloned(), linear(m, &name), "{}", name);
+            }
+        };
+        check(&m);
+        let last = m.tasks.iter().map(|(_, t)| t.line).max().unwrap();
+        assert_eq!(m.tasks[m.get_id("t0").unwrap()].line, last - 249);
+
+        let id = m.get_id("t7").unwrap().clone();
+        m.tasks.remove(&id);
+        m.tasks.retain(|_, t| t.name != "t9");
+        check(&m);
+        assert!(m.get_id("t7").is_some());
+        assert!(m.get_id("t9").is_none());
+    }
+
+    #[test]
+    fn test_find_cycles() {
+        let cases = [
+            ("a: b\nb: a\n", vec![vec!["a", "b"]]),
+            ("a: a\n", vec![vec!["a"]]),
+            ("a: b c\nb: c\nc:\n", vec![]),
+            (
+                "a: b\nb: c\nc: a b\n",
+                vec![vec!["a", "b", "c"], vec!["b", "c"]],
+            ),
+            ("x: a\na: b\nb: a\ny: b\n", vec![vec!["a", "b"]]),
+        ];
+
+        for (i, (data, expected)) in cases.into_iter().enumerate() {
+            let m = Makefile::from_terms(
+                &mut IDGen::new("task"),
+                &mut HashSet::new(),
+                PathBuf::from("Makefile"),
+                parser::Makefile::parse(data).unwrap(),
+                &Walker::default(),
+            );
+            let cycles = m
+                .find_cycles()
+                .iter()
+                .map(|c| c.iter().map(|id| m.tasks[id].name.as_str()).collect())
+                .collect::<Vec<Vec<&str>>>();
+            assert_eq!(cycles, expected, "case {:02}", i);
+        }
+    }
+
+    #[test]
+    fn test_topo_order() {
+        let cases = [
+            (
+                "top: left right\nleft: bottom\nright: bottom\nbottom:\n",
+                Ok(vec!["bottom", "left", "right", "top"]),
+            ),
+            (
+                "bottom:\nright: bottom\nleft: bottom\ntop: left right\n",
+                Ok(vec!["bottom", "right", "left", "top"]),
+            ),
+            (
+                "top: right left\nleft: bottom\nright: bottom\nbottom:\nextra:\n",
+                Ok(vec!["bottom", "right", "left", "top", "extra"]),
+            ),
+            ("top: left\nleft: top\n", Err(vec!["top", "left"])),
+        ];
+
+        for (i, (data, expected)) in cases.into_iter().enumerate() {
+            let m = Makefile::from_terms(
+                &mut IDGen::new("task"),
+                &mut HashSet::new(),
+                PathBuf::from("Makefile"),
+                parser::Makefile::parse(data).unwrap(),
+                &Walker::default(),
+            );
+            let names = |ids: Vec<ID>| {
+                ids.iter()
+                    .map(|id| m.tasks[id].name.as_str())
+                    .collect::<Vec<&str>>()
+            };
+            let order = m.topo_order().map(names).map_err(|c| names(c.0));
+            assert_eq!(order, expected, "case {:02}", i);
+        }
+    }
+
+    #[test]
+    fn test_reachable_from() {
+        let data = "deploy: left right\nleft: base\nright: base | dir\nbase:\ndir:\nother: base\n";
+        let m = Makefile::from_terms(
+            &mut IDGen::new("task"),
+            &mut HashSet::new(),
+            PathBuf::from("Makefile"),
+            parser::Makefile::parse(data).unwrap(),
+            &Walker::default(),
+        );
+        let cases = [
+            ("deploy", vec!["base", "deploy", "dir", "left", "right"]),
+            ("right", vec!["base", "dir", "right"]),
+            ("base", vec!["base"]),
+            ("missing", vec![]),
+        ];
+
+        for (i, (name, expected)) in cases.into_iter().enumerate() {
+            let mut names = m
+                .reachable_from(name)
+                .iter()
+                .map(|id| m.tasks[id].name.as_str())
+                .collect::<Vec<&str>>();
+            names.sort();
+            assert_eq!(names, expected, "case {:02}", i);
+        }
+    }
+
+    #[test]
+    fn test_default_goal() {
+        let cases = [
+            (".PHONY: all\n%.o: %.c\nall: lib\nlib:\n", Some("all")),
+            (".SUFFIXES:\n.c.o:\nstamp:\nall:\n", Some("stamp")),
+            ("z:\ny:\nx:\n", Some("z")),
+            ("lib:\nall:\n.DEFAULT_GOAL := all\n", Some("all")),
+            ("GOAL = b\na:\nb:\n.DEFAULT_GOAL = $(GOAL)\n", Some("b")),
+            (".PHONY: all\n", None),
+            ("", None),
+        ];
+
+        for (i, (data, expected)) in cases.into_iter().enumerate() {
+            let m = Makefile::from_terms(
+                &mut IDGen::new("task"),
+                &mut HashSet::new(),
+                PathBuf::from("Makefile"),
+                parser::Makefile::parse(data).unwrap(),
+                &Walker::default(),
+            );
+            let goal = m.default_goal().map(|id| m.tasks[id].name.as_str());
+            assert_eq!(goal, expected, "case {:02}", i);
+        }
+    }
+
+    #[test]
+    fn test_default_goal_call() {
+        let root = fixture(
+            "default_goal_call",
+            &[
+                ("Makefile", "all:\n\tmake -C lib\n\tmake -C doc\n"),
+                (
+                    "lib/Makefile",
+                    ".PHONY: build\n%.o: %.c\nbuild: lib.a\nlib.a:\n",
+                ),
+                ("doc/Makefile", ""),
+            ],
+        );
+        let graph = Makefile::walk_from(root.join("Makefile")).unwrap();
+        let mut externals = graph
+            .externals
+            .iter()
+            .map(|e| (e.path.strip_prefix(&root).unwrap(), e.tasks.clone()))
+            .collect::<Vec<_>>();
+        externals.sort();
+        assert_eq!(
+            externals,
+            vec![
+                (Path::new("doc/Makefile"), vec![]),
+                (Path::new("lib/Makefile"), vec!["build".to_string()]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_same_makefile_externals() {
+        let root = fixture(
+            "same_makefile_externals",
+            &[
+                ("Makefile", "all:\n\tmake -C lib a\n\tmake -C lib b\n"),
+                ("lib/Makefile", "a:\nb:\n"),
+            ],
+        );
+        let graph = Makefile::walk_from(root.join("Makefile")).unwrap();
+        assert_eq!(graph.makefiles.len(), 2);
+        assert_eq!(graph.externals.len(), 2);
+
+        let mut out = Vec::new();
+        crate::render::write_dot(&mut out, &graph, &Default::default()).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let all = graph.find_task("all").unwrap();
+        for name in ["a", "b"] {
+            let id = graph.makefiles[1].get_id(name).unwrap();
+            assert!(out.contains(&format!("\t{} -> {}\n", all, id)), "{}", out);
+        }
+    }
+
+    #[test]
+    fn test_resolutions() {
+        let root = fixture(
+            "resolutions",
+            &[
+                ("Makefile", "all:\n\tmake -C lib build\n"),
+                ("stub/lib.mk", "build:\n"),
+            ],
+        );
+        let graph = Walker::new(root.join("Makefile")).walk().unwrap();
+        assert_eq!(graph.unresolved.len(), 1);
+
+        let stub = root.join("stub/lib.mk");
+        let graph = Walker::new(root.join("Makefile"))
+            .resolutions(HashMap::from([("lib".to_string(), stub.clone())]))
+            .walk()
+            .unwrap();
+        assert!(graph.unresolved.is_empty(), "{:?}", graph.unresolved);
+        assert_eq!(graph.makefiles.len(), 2);
+        assert_eq!(graph.externals.iter().next().unwrap().path, stub);
+    }
+
+    #[test]
+    fn test_dynamic_external() {
+        let root = fixture(
+            "dynamic_external",
+            &[(
+                "Makefile",
+                "all:\n\tOUT=build/$(ARCH); \\\n\tmake -C \"$OUT\"\n",
+            )],
+        );
+        let graph = Makefile::walk_from(root.join("Makefile")).unwrap();
+        assert!(graph.unresolved.is_empty(), "{:?}", graph.unresolved);
+        assert_eq!(graph.dynamic.len(), 1);
+        assert_eq!(graph.dynamic[0].path, "\"$OUT\"");
+        assert_eq!(Some(&graph.dynamic[0].id), graph.find_task("all"));
+    }
+
+    #[test]
+    fn test_merge() {
+        let root = fixture(
+            "merge",
+            &[
+                (
+                    "Makefile",
+                    "CC = gcc\nCFLAGS = -O2\n.PHONY: all\nall: lib\n",
+                ),
+                ("lib.mk", "CC = clang\n.PHONY: lib\nlib:\nclean:\n"),
+            ],
+        );
+        let mut m = Makefile::open(root.join("Makefile")).unwrap();
+        let other = Makefile::open(root.join("lib.mk")).unwrap();
+        let mut id = IDGen::new("merged");
+        m.merge(&mut id, other);
+
+        assert_eq!(m.variables["CC"].value, "clang");
+        for task in m.tasks.values() {
+            let origin = match task.name.as_str() {
+                "lib" | "clean" => "lib.mk",
+                "all" => "Makefile",
+                _ => continue,
+            };
+            assert_eq!(task.origin_file, root.join(origin).canonicalize().unwrap());
+        }
+        assert_eq!(m.variables["CFLAGS"].value, "-O2");
+        let mut tasks = m
+            .tasks
+            .iter()
+            .map(|(id, t)| (t.name.as_str(), t.phony, id.starts_with("merged")))
+            .collect::<Vec<_>>();
+        tasks.sort();
+        assert_eq!(
+            tasks,
+            vec![
+                (".PHONY", false, false),
+                (".PHONY", false, true),
+                ("all", true, false),
+                ("clean", false, true),
+                ("lib", true, true),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_inherited_variables() {
+        let sub = "LOCAL ?= local\nbuild: $(DIR)-lib $(HIDDEN)y $(LOCAL)\n";
+        let cases = [
+            (
+                "export DIR = out\nHIDDEN = x\n",
+                vec!["out-lib", "y", "local"],
+            ),
+            (
+                "DIR = out\nHIDDEN = $(DIR)\n.EXPORT_ALL_VARIABLES:\n",
+                vec!["out-lib", "outy", "local"],
+            ),
+            ("export LOCAL = $$x\n", vec!["-lib", "y", "$x"]),
+            ("DIR = out\n", vec!["-lib", "y", "local"]),
+        ];
+
+        for (i, (vars, expected)) in cases.into_iter().enumerate() {
+            let root = fixture(
+                &format!("inherited_variables_{}", i),
+                &[
+                    ("Makefile", &format!("{}all:\n\t$(MAKE) -C sub\n", vars)),
+                    ("sub/Makefile", sub),
+                ],
+            );
+            let graph = Makefile::walk_from(root.join("Makefile")).unwrap();
+            let m = &graph.makefiles[1];
+            let build = m.tasks.values().find(|t| t.name == "build").unwrap();
+            assert_eq!(build.dependencies, expected, "case {:02}", i);
+        }
+    }
+
+    #[test]
+    fn test_export_all() {
+        let cases = [
+            ("A = 1\n.EXPORT_ALL_VARIABLES:\nall:\n", true),
+            ("A = 1\nall:\n", false),
+        ];
+
+        for (i, (data, expected)) in cases.into_iter().enumerate() {
+            let root = fixture(&format!("export_all_{}", i), &[("Makefile", data)]);
+            let m = Makefile::open(root.join("Makefile")).unwrap();
+            assert_eq!(m.export_all, expected, "case {:02}", i);
+            let names = m
+                .tasks
+                .values()
+                .map(|t| t.name.as_str())
+                .collect::<Vec<&str>>();
+            assert_eq!(names, vec!["all"], "case {:02}", i);
+        }
+    }
+
+    #[test]
+    fn test_expanded_names() {
+        let root = fixture(
+            "expanded_names",
+            &[(
+                "Makefile",
+                "PROG = app\nOBJS = a.o b.o\n.PHONY: $(PROG)\n$(PROG): $(OBJS)\na.o:\n",
+            )],
+        );
+        let m = Makefile::open(root.join("Makefile")).unwrap();
+        let id = m.get_id("app").expect("task named app");
+        let task = &m.tasks[id];
+        assert_eq!(task.dependencies, vec!["a.o", "b.o"]);
+        assert!(task.phony);
+        assert!(m.get_id("$(PROG)").is_none());
+    }
+
+    #[test]
+    fn test_duplicate_recipes() {
+        let root = fixture(
+            "duplicate_recipes",
+            &[(
+                "Makefile",
+                "a:\n\tcc -c $<\n\techo done\nb:\n\tcc -c $<\n\techo done\nc:\n\tcc -c $<\nd:\ne:\n",
+            )],
+        );
+        let m = Makefile::open(root.join("Makefile")).unwrap();
+        assert_eq!(m.duplicate_recipes(), vec![vec!["a", "b"]]);
+    }
+
+    #[test]
+    fn test_expand_home() {
+        let home = Some(Path::new("/home/me"));
+        let cases = [
+            ("~/x.mk", home, "/home/me/x.mk"),
+            ("~", home, "/home/me"),
+            ("~other/x.mk", home, "/home/other/x.mk"),
+            ("dir/~/x.mk", home, "dir/~/x.mk"),
+            ("~/x.mk", None, "~/x.mk"),
+        ];
+
+        for (i, (path, home, expected)) in cases.into_iter().enumerate() {
+            let result = super::expand_home(path, home);
+            assert_eq!(
+                result,
+                Path::new(expected),
+                "case {:02}, path: {:?}",
+                i,
+                path
+            );
+        }
+
+        let root = fixture(
+            "expand_home",
+            &[
+                ("project/Makefile", "all:\n\tmake -f ~/x.mk build\n"),
+                ("home/x.mk", "build:\n"),
+            ],
+        );
+        let graph = Walker::new(root.join("project/Makefile"))
+            .home(root.join("home"))
+            .walk()
+            .unwrap();
+        assert!(graph.unresolved.is_empty(), "{:?}", graph.unresolved);
+        let external = graph.externals.iter().next().unwrap();
+        assert_eq!(
+            external.path,
+            root.join("home/x.mk").canonicalize().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_observer() {
+        let root = fixture(
+            "observer",
+            &[
+                (
+                    "Makefile",
+                    "all:\n\tmake -f sub/Makefile lib\n\tmake -f sub/Makefile test\n",
+                ),
+                ("sub/Makefile", "lib:\n\tmake -f ../other.mk gen\ntest:\n"),
+                ("other.mk", "gen:\n"),
+            ],
+        );
+        let mut seen = Vec::new();
+        let graph =
+            Makefile::walk_from_with_observer(root.join("Makefile"), |e| seen.push(e.clone()))
+                .unwrap();
+        assert_eq!(seen.len(), 3);
+        assert_eq!(seen.into_iter().collect::<HashSet<_>>(), graph.externals);
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = fixture(
+            "include",
+            &[
+                (
+                    "Makefile",
+                    "BUILD = build\ninclude $(BUILD)/rules.mk other.mk\n-include gen.mk\nall: rules other\n",
+                ),
+                ("build/rules.mk", "rules:\n"),
+                ("other.mk", "include build/rules.mk\nother:\n"),
+            ],
+        );
+        let graph = Makefile::walk_from(dir.join("Makefile")).unwrap();
+        let mut files = graph
+            .makefiles
+            .iter()
+            .map(|m| m.file.strip_prefix(&dir).unwrap().to_path_buf())
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(
+            files,
+            ["Makefile", "build/rules.mk", "other.mk"].map(PathBuf::from)
+        );
+        let root = &graph.makefiles[0];
+        let includes = root
+            .includes
+            .iter()
+            .map(|i| (i.path.as_str(), i.optional))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            includes,
+            [
+                ("build/rules.mk", false),
+                ("other.mk", false),
+                ("gen.mk", true)
+            ]
+        );
+        for name in ["rules", "other"] {
+            assert!(
+                graph
+                    .makefiles
+                    .iter()
+                    .any(|m| m.tasks.values().any(|t| t.name == name)),
+                "{name} not found"
+            );
+        }
+    }
+
+    #[test]
+    fn test_merge_includes() {
+        let dir = fixture(
+            "merge_includes",
+            &[
+                (
+                    "Makefile",
+                    "include rules.mk\n-include gen.mk\nall: rules other\n\tmake -C sub\n",
+                ),
+                ("rules.mk", "include other.mk\nrules:\n"),
+                ("other.mk", "include rules.mk\nother:\n\tmake -C sub sub\n"),
+                ("sub/Makefile", "sub:\n"),
+            ],
+        );
+        let graph = Walker::new(dir.join("Makefile"))
+            .merge_includes(true)
+            .walk()
+            .unwrap();
+        let files = graph
+            .makefiles
+            .iter()
+            .map(|m| m.file.strip_prefix(&dir).unwrap().to_path_buf())
+            .collect::<Vec<_>>();
+        assert_eq!(files, ["Makefile", "sub/Makefile"].map(PathBuf::from));
+
+        let root = &graph.makefiles[0];
+        let mut tasks = root
+            .tasks
+            .values()
+            .map(|t| (t.name.as_str(), t.origin_file.strip_prefix(&dir).unwrap()))
+            .collect::<Vec<_>>();
+        tasks.sort();
+        assert_eq!(
+            tasks,
+            [
+                ("all", Path::new("Makefile")),
+                ("other", Path::new("other.mk")),
+                ("rules", Path::new("rules.mk")),
+            ]
+        );
+        let all = root.get_id("all").unwrap();
+        let deps = root.dependency_ids(all);
+        assert_eq!(deps.len(), 2);
+        // Calls from merged tasks keep pointing at them.
+        let mut callers = graph
+            .externals
+            .iter()
+            .map(|e| root.tasks[&e.id].name.as_str())
+            .collect::<Vec<&str>>();
+        callers.sort();
+        assert_eq!(callers, ["all", "other"]);
+    }
+
+    #[test]
+    fn test_include_cycle() {
+        let files = [
+            ("/p/Makefile", "include a.mk\nall:\n"),
+            ("/p/a.mk", "include b.mk\na:\n"),
+            ("/p/b.mk", "include a.mk\nb:\n"),
+        ];
+        let files = files
+            .into_iter()
+            .map(|(path, data)| (PathBuf::from(path), data.to_string()))
+            .collect::<HashMap<PathBuf, String>>();
+        let expected = vec![["/p/a.mk", "/p/b.mk", "/p/a.mk"]
+            .map(PathBuf::from)
+            .to_vec()];
+
+        for merge in [false, true] {
+            let graph = Walker::new("/p/Makefile")
+                .files(files.clone())
+                .merge_includes(merge)
+                .walk()
+                .unwrap();
+            let cycles = graph
+                .makefiles
+                .iter()
+                .flat_map(|m| m.include_cycles.clone())
+                .collect::<Vec<Vec<PathBuf>>>();
+            assert_eq!(cycles, expected, "merge: {}", merge);
+            assert!(graph.find_task("b").is_some(), "merge: {}", merge);
+        }
+    }
+
+    #[test]
+    fn test_include_order() {
+        let files = [
+            (
+                "/p/Makefile",
+                "EARLY = parent\nBEFORE := $(SUBDIR)\ninclude config.mk\nAFTER := $(SUBDIR)\nLATE = parent\n",
+            ),
+            ("/p/config.mk", "SUBDIR = sub\nEARLY = config\nLATE = config\n"),
+        ];
+        let files = files
+            .into_iter()
+            .map(|(path, data)| (PathBuf::from(path), data.to_string()))
+            .collect();
+        let graph = Walker::new("/p/Makefile")
+            .files(files)
+            .merge_includes(true)
+            .walk()
+            .unwrap();
+        let m = &graph.makefiles[0];
+        let cases = [
+            ("BEFORE", ""),
+            ("AFTER", "sub"),
+            ("EARLY", "config"),
+            ("LATE", "parent"),
+        ];
+        for (i, (name, expected)) in cases.into_iter().enumerate() {
+            let result = m.resolve_vars(&VarStr(format!("$({})", name)));
+            assert_eq!(result, expected, "case {:02}, variable: {}", i, name);
+        }
+    }
+
+    #[test]
+    fn test_in_memory() {
+        let cases = [
+            ("/a/./b/../c", "/a/c"),
+            ("/../a", "/a"),
+            ("a/../../b", "../b"),
+            ("./a/", "a"),
+        ];
+        for (i, (path, expected)) in cases.into_iter().enumerate() {
+            let result = super::normalize(Path::new(path));
+            assert_eq!(
+                result,
+                Path::new(expected),
+                "case {:02}, path: {:?}",
+                i,
+                path
+            );
+        }
+
+        let files = [
+            (
+                "/proj/Makefile",
+                "all:\n\tmake -f ./sub/../lib/Makefile build\n\tmake -C lib\n",
+            ),
+            ("/proj/lib/Makefile", "build:\n"),
+        ];
+        let files = files
+            .into_iter()
+            .map(|(path, data)| (PathBuf::from(path), data.to_string()))
+            .collect();
+        let graph = Walker::new("/proj/Makefile").files(files).walk().unwrap();
+        assert!(graph.unresolved.is_empty(), "{:?}", graph.unresolved);
+        let walked = graph
+            .makefiles
+            .iter()
+            .map(|m| m.file.as_path())
+            .collect::<Vec<&Path>>();
+        assert_eq!(
+            walked,
+            vec![Path::new("/proj/Makefile"), Path::new("/proj/lib/Makefile")]
+        );
+        assert!(graph
+            .externals
+            .iter()
+            .all(|e| e.path == Path::new("/proj/lib/Makefile")));
+    }
+
+    #[test]
+    fn test_define() {
+        let input = "define CMD =\necho hello\necho done\nendef\nCMD += !\n";
+        let terms = parser::Makefile::parse(input).unwrap();
+        let m = Makefile::from_terms(
+            &mut IDGen::new("task"),
+            &mut HashSet::new(),
+            PathBuf::from("Makefile"),
+            terms,
+            &Walker::default(),
+        );
+        assert_eq!(m.variables["CMD"].value, "echo hello\necho done !");
+        let resolved = m.resolve_vars(&VarStr("run: $(CMD)".to_string()));
+        assert_eq!(resolved, "run: echo hello\necho done !");
+    }
+
+    #[test]
+    fn test_canned_recipe() {
+        let files = [
+            (
+                "/p/Makefile",
+                "SUB = sub\ndefine BUILD_SUB\n@echo building\n$(MAKE) -C $(SUB) build\nendef\nall:\n\t$(BUILD_SUB)\n",
+            ),
+            ("/p/sub/Makefile", "build:\n"),
+        ];
+        let files = files
+            .into_iter()
+            .map(|(path, data)| (PathBuf::from(path), data.to_string()))
+            .collect();
+        let graph = Walker::new("/p/Makefile").files(files).walk().unwrap();
+
+        assert!(graph.unresolved.is_empty(), "{:?}", graph.unresolved);
+        assert_eq!(graph.externals.len(), 1);
+        let external = graph.externals.iter().next().unwrap();
+        assert_eq!(external.path, Path::new("/p/sub/Makefile"));
+        assert_eq!(external.tasks, vec!["build"]);
+        assert_eq!(Some(&external.id), graph.find_task("all"));
+    }
+
+    #[test]
+    fn test_static_pattern() {
+        let input = "OBJS = foo.o bar.o baz.o\nall: $(OBJS)\n\
+                     $(OBJS): %.o: %.c common.h\n\tcc -c $< -o $@\n";
+        let terms = parser::Makefile::parse(input).unwrap();
+        let m = Makefile::from_terms(
+            &mut IDGen::new("task"),
+            &mut HashSet::new(),
+            PathBuf::from("Makefile"),
+            terms,
+            &Walker::default(),
+        );
+        assert_eq!(m.tasks.len(), 4);
+        for name in ["foo", "bar", "baz"] {
+            let id = m.get_id(&format!("{}.o", name)).unwrap();
+            let task = &m.tasks[id];
+            assert_eq!(
+                task.dependencies,
+                [format!("{}.c", name), "common.h".to_string()]
+            );
+            assert_eq!(task.commands, ["cc -c $< -o $@"]);
+        }
+        assert_eq!(super::pattern_stem("%.o", "foo.c"), None);
+        assert_eq!(super::pattern_stem("lib/%.o", "lib/a.o"), Some("a"));
+    }
+
+    #[test]
+    fn test_append() {
+        let var = |name, op, value| {
+            Term::Variable(Variable {
+                modifiers: vec![],
+                name,
+                op,
+                value,
+            })
+        };
+        let cases = [
+            (vec![var("X", "=", "a"), var("X", "+=", "b")], "a b"),
+            (vec![var("X", "+=", "b")], "b"),
+            (vec![var("X", "=", ""), var("X", "+=", "b")], "b"),
+            (
+                vec![var("X", "=", "a"), var("X", "+=", "b"), var("X", "+=", "c")],
+                "a b c",
+            ),
+            (vec![var("X", "+=", "b"), var("X", "=", "a")], "a"),
+        ];
+
+        for (i, (terms, expected)) in cases.into_iter().enumerate() {
+            let m = Makefile::from_terms(
+                &mut IDGen::new("task"),
+                &mut HashSet::new(),
+                PathBuf::from("Makefile"),
+                terms,
+                &Walker::default(),
+            );
+            assert_eq!(m.variables["X"].value, expected, "case {:02}", i);
+        }
+    }
+
+    #[test]
+    fn test_lint_phony() {
+        let root = fixture(
+            "lint_phony",
+            &[(
+                "Makefile",
+                ".PHONY: all out.txt\nall: app out.txt\napp: main.o\n\tcc -o app main.o\n\
+                 out.txt:\n\techo hi > $@\nclean:\n\trm -f app\ndocs:\n\tmkdocs build\n",
+            )],
+        );
+        let m = Makefile::open(root.join("Makefile")).unwrap();
+        let (undeclared, misdeclared) = m.lint_phony();
+        assert_eq!(undeclared, vec!["clean", "docs"]);
+        assert_eq!(misdeclared, vec!["out.txt"]);
+    }
+
+    #[test]
+    fn test_ordering_assumptions() {
+        let root = fixture(
+            "ordering_assumptions",
+            &[(
+                "Makefile",
+                "all: gen.h app lib.a\napp:\n\tcc -include gen.h -o app\n\
+                 lib.a: gen.h\n\tar rcs lib.a gen.h\ngen.h:\n\techo '#pragma once' > gen.h\n",
+            )],
+        );
+        let m = Makefile::open(root.join("Makefile")).unwrap();
+        assert_eq!(m.ordering_assumptions(), vec![("all", "app", "gen.h")]);
+    }
+
+    #[test]
+    fn test_exported() {
+        let cases = [
+            ("export A = 1\n", vec!["A"], false),
+            ("A = 1\nexport A\n", vec!["A"], false),
+            ("export A B\n", vec!["A", "B"], false),
+            ("export A = 1\nunexport A\n", vec![], false),
+            ("override A = 1\n", vec![], false),
+            ("export override A = 1\n", vec!["A"], false),
+            ("NAMES = A B\nexport $(NAMES)\n", vec!["A", "B"], false),
+            ("export\n", vec![], true),
+            ("export\nunexport\n", vec![], false),
+        ];
+
+        for (i, (data, expected, export_all)) in cases.into_iter().enumerate() {
+            let m = Makefile::from_terms(
+                &mut IDGen::new("task"),
+                &mut HashSet::new(),
+                PathBuf::from("Makefile"),
+                parser::Makefile::parse(data).unwrap(),
+                &Walker::default(),
+            );
+            let mut exported = m.exported.iter().map(String::as_str).collect::<Vec<_>>();
+            exported.sort();
+            assert_eq!(exported, expected, "case {:02}", i);
+            assert_eq!(m.export_all, export_all, "case {:02}", i);
+            assert!(m.tasks.is_empty(), "case {:02}", i);
+        }
+
+        let m = Makefile::from_terms(
+            &mut IDGen::new("task"),
+            &mut HashSet::new(),
+            PathBuf::from("Makefile"),
+            parser::Makefile::parse("export A = 1\noverride B := 2\n").unwrap(),
+            &Walker::default(),
+        );
+        assert_eq!(m.variables["A"].value, "1");
+        assert_eq!(m.variables["B"].value, "2");
+    }
+
+    #[test]
+    fn test_shell_assignment() {
+        let data = "GIT_REV != git rev-parse HEAD\nCC != which cc\nCC = gcc\n";
+        let terms = parser::Makefile::parse(data).unwrap();
+        assert!(matches!(
+            terms[0],
+            Term::Variable(Variable { op: "!=", .. })
+        ));
+
+        let m = Makefile::from_terms(
+            &mut IDGen::new("task"),
+            &mut HashSet::new(),
+            PathBuf::from("Makefile"),
+            terms,
+            &Walker::default(),
+        );
+        assert_eq!(m.variables["GIT_REV"].value, "git rev-parse HEAD");
+        assert_eq!(m.shell_assigned, HashSet::from(["GIT_REV".to_string()]));
+    }
+
+    #[test]
+    fn test_conditional_tasks() {
+        let root = fixture(
+            "conditional_tasks",
+            &[(
+                "Makefile",
+                "all: a b\nifdef DEBUG\na:\n\techo debug\nelse\nb:\nendif\n",
+            )],
+        );
+        let m = Makefile::open(root.join("Makefile")).unwrap();
+        let mut names = m
+            .tasks
+            .values()
+            .map(|t| t.name.as_str())
+            .collect::<Vec<&str>>();
+        names.sort();
+        assert_eq!(names, vec!["a", "all", "b"]);
+    }
+
+    #[test]
+    fn test_conditional_phony() {
+        let root = fixture(
+            "conditional_phony",
+            &[(
+                "Makefile",
+                "all: x y\nifeq (a,a)\n.PHONY: x\nendif\nx:\n\tgen\ny:\n\tgen\n",
+            )],
+        );
+        let m = Makefile::open(root.join("Makefile")).unwrap();
+        let phony = |name| m.tasks[m.get_id(name).unwrap()].phony;
+        assert!(phony("x"));
+        assert!(!phony("y"));
+    }
+
+    #[test]
+    fn test_eval_condition() {
+        let data = "OS = Windows_NT\nEMPTY =\nREV != git rev-parse HEAD\n";
+        let terms = parser::Makefile::parse(data).unwrap();
+        let m = Makefile::from_terms(
+            &mut IDGen::new("task"),
+            &mut HashSet::new(),
+            PathBuf::from("Makefile"),
+            terms,
+            &Walker::default(),
+        );
+        let cases = [
+            ("ifeq", "$(OS)", "Windows_NT", Some(true)),
+            ("ifneq", "$(OS)", "Windows_NT", Some(false)),
+            ("ifeq", "${OS}", "Linux", Some(false)),
+            ("ifeq", "a", "a", Some(true)),
+            ("ifeq", "$(ARCH)", "x86", None),
+            ("ifeq", "$(REV)", "abc", None),
+            ("ifdef", "OS", "", Some(true)),
+            ("ifndef", "OS", "", Some(false)),
+            ("ifdef", "EMPTY", "", Some(false)),
+            ("ifdef", "ARCH", "", None),
+            ("ifeq", "$$(ARCH)", "$$(ARCH)", Some(true)),
+            ("ifeq", "$$$(OS)", "$$Windows_NT", Some(true)),
+            ("ifeq", "$(shell uname)", "Linux", None),
+            ("ifeq", "$(origin CC)", "undefined", None),
+            ("ifeq", "$(CC_$(ARCH))", "gcc", None),
+            ("ifeq", "$(OS_$(OS))", "", None),
+            ("ifeq", "$(firstword $(OS) x)", "Windows_NT", Some(true)),
+            ("ifdef", "$(shell echo OS)", "", None),
+        ];
+
+        for (i, (kind, lhs, rhs, expected)) in cases.into_iter().enumerate() {
+            let result = m.eval_condition(kind, lhs, rhs);
+            assert_eq!(result, expected, "case {:02}, {} {} {}", i, kind, lhs, rhs);
+        }
+
+        let root = fixture(
+            "eval_condition",
+            &[(
+                "Makefile",
+                "OS = Windows_NT\nifeq ($(OS),Windows_NT)\nwin:\nelse\nunix:\nendif\n\
+                 ifeq ($(ARCH),x86)\nx86:\nelse\nother:\nendif\n\
+                 ifeq ($(shell uname),Linux)\nlinux:\nelse\nnotlinux:\nendif\n",
+            )],
+        );
+        let m = Makefile::open(root.join("Makefile")).unwrap();
+        let mut names = m
+            .tasks
+            .values()
+            .map(|t| t.name.as_str())
+            .collect::<Vec<&str>>();
+        names.sort();
+        assert_eq!(names, vec!["linux", "notlinux", "other", "win", "x86"]);
+    }
+
+    #[test]
+    fn test_recipe_conditional() {
+        let cases = [
+            ("X = y\n", vec!["echo one", "echo two"]),
+            ("X = n\n", vec!["echo one", "echo three"]),
+            // Undecidable, so both branches are kept.
+            ("", vec!["echo one", "echo two", "echo three"]),
+        ];
+
+        for (i, (vars, expected)) in cases.into_iter().enumerate() {
+            let data = format!(
+                "{}all:\n\techo one\nifeq ($(X),y)\n\techo two\nelse\n\techo three\nendif\n",
+                vars
+            );
+            let terms = parser::Makefile::parse(&data).unwrap();
+            let m = Makefile::from_terms(
+                &mut IDGen::new("task"),
+                &mut HashSet::new(),
+                PathBuf::from("Makefile"),
+                terms,
+                &Walker::default(),
+            );
+            let all = m.tasks.values().find(|t| t.name == "all").unwrap();
+            assert_eq!(all.commands, expected, "case {:02}", i);
+        }
+    }
+
+    #[test]
+    fn test_duplicate_targets() {
+        let data = "foo: a\n\techo one\nfoo: b\n\techo two\na:\nb:\n";
+        let root = fixture("duplicate_targets", &[("Makefile", data)]);
+        let terms = parser::Makefile::parse(data).unwrap();
+        assert_eq!(super::overridden_recipes(&terms), vec!["foo"]);
+
+        let count = |m: &Makefile| m.tasks.values().filter(|t| t.name == "foo").count();
+        let graph = Walker::new(root.join("Makefile")).walk().unwrap();
+        assert_eq!(count(&graph.makefiles[0]), 2);
+
+        let graph = Walker::new(root.join("Makefile"))
+            .merge_duplicates(true)
+            .walk()
+            .unwrap();
+        let m = &graph.makefiles[0];
+        assert_eq!(count(m), 1);
+        let foo = &m.tasks[m.get_id("foo").unwrap()];
+        assert_eq!(foo.dependencies, vec!["a", "b"]);
+        assert_eq!(foo.commands, vec!["echo two"]);
+    }
+
+    #[test]
+    fn test_multiple_targets() {
+        let input = "GEN = c.h d.h\na.h b.h $(GEN): gen.py | out\n\tpython gen.py\n";
+        let terms = parser::Makefile::parse(input).unwrap();
+        let m = Makefile::from_terms(
+            &mut IDGen::new("task"),
+            &mut HashSet::new(),
+            PathBuf::from("Makefile"),
+            terms,
+            &Walker::default(),
+        );
+        let mut names = m
+            .tasks
+            .values()
+            .map(|t| t.name.as_str())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["a.h", "b.h", "c.h", "d.h"]);
+        for task in m.tasks.values() {
+            assert_eq!(task.dependencies, ["gen.py"]);
+            assert_eq!(task.order_only, ["out"]);
+            assert_eq!(task.commands, ["python gen.py"]);
+            assert_eq!(task.line, 2);
+        }
+    }
+
+    #[test]
+    fn test_target_variables() {
+        let input = "CFLAGS = -O2\ndebug: CFLAGS += -g\ndebug release: LDFLAGS := -s\ndebug: app\n";
+        let terms = parser::Makefile::parse(input).unwrap();
+        let m = Makefile::from_terms(
+            &mut IDGen::new("task"),
+            &mut HashSet::new(),
+            PathBuf::from("Makefile"),
+            terms,
+            &Walker::default(),
+        );
+        assert_eq!(m.tasks.len(), 1);
+        let debug = &m.tasks[m.get_id("debug").unwrap()];
+        assert_eq!(debug.dependencies, ["app"]);
+        let var = |name: &str, op: &str, value: &str| {
+            (name.to_string(), op.to_string(), value.to_string())
+        };
+        assert_eq!(
+            debug.local_vars,
+            [var("CFLAGS", "+=", "-g"), var("LDFLAGS", ":=", "-s")]
+        );
+        assert_eq!(m.variables["CFLAGS"].value, "-O2");
+    }
+
+    #[test]
+    fn test_double_colon() {
+        let data = "clean:: a\n\trm -f a\nclean:: b\n\trm -f b\na:\nb:\n";
+        let root = fixture("double_colon", &[("Makefile", data)]);
+        let terms = parser::Makefile::parse(data).unwrap();
+        assert!(super::overridden_recipes(&terms).is_empty());
+
+        let graph = Walker::new(root.join("Makefile"))
+            .merge_duplicates(true)
+            .walk()
+            .unwrap();
+        let mut cleans = graph.makefiles[0]
+            .tasks
+            .values()
+            .filter(|t| t.name == "clean")
+            .map(|t| (t.double_colon, t.dependencies.clone(), t.commands.clone()))
+            .collect::<Vec<_>>();
+        cleans.sort();
+        assert_eq!(
+            cleans,
+            [
+                (true, vec!["a".to_string()], vec!["rm -f a".to_string()]),
+                (true, vec!["b".to_string()], vec!["rm -f b".to_string()]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_always_rebuilt() {
+        let root = fixture(
+            "always_rebuilt",
+            &[(
+                "Makefile",
+                ".PHONY: clean all\nout: clean\nall: out\nclean:\nok: clean src.c\n",
+            )],
+        );
+        let graph = Makefile::walk_from(root.join("Makefile")).unwrap();
+        assert_eq!(graph.makefiles[0].always_rebuilt(), vec!["out"]);
+    }
+
+    #[test]
+    fn test_dollar_escape() {
+        let data = "X = x\nY = $$X\n";
+        let m = Makefile::from_terms(
+            &mut IDGen::new("task"),
+            &mut HashSet::new(),
+            PathBuf::from("Makefile"),
+            parser::Makefile::parse(data).unwrap(),
+            &Walker::default(),
+        );
+        let cases = [
+            ("${X}$$literal", "x$literal"),
+            ("$(X)$$(X)", "x$(X)"),
+            ("$$$(X)", "$x"),
+            ("echo $$HOME", "echo $HOME"),
+            ("$(Y)", "$X"),
+        ];
+
+        for (i, (input, expected)) in cases.into_iter().enumerate() {
+            let result = m.resolve_vars(&VarStr(input.to_string()));
+            assert_eq!(result, expected, "case {:02}", i);
+        }
+    }
+
+    #[test]
+    fn test_immediate_assignment() {
+        let data = "\
+DEFERRED = $(B)
+IMMEDIATE := $(B)
+POSIX ::= $(B)
+B = late
+X := 1
+Y := $(X)
+X := $(X) 2
+S := $(B)
+S += $(X)
+R = $(B)
+R += $(X)
+D ?= default
+B ?= ignored
+";
+        let m = Makefile::from_terms(
+            &mut IDGen::new("task"),
+            &mut HashSet::new(),
+            PathBuf::from("Makefile"),
+            parser::Makefile::parse(data).unwrap(),
+            &Walker::default(),
+        );
+        let cases = [
+            ("DEFERRED", "=", "$(B)", "late"),
+            ("IMMEDIATE", ":=", "", ""),
+            ("POSIX", "::=", "", ""),
+            ("Y", ":=", "1", "1"),
+            ("X", ":=", "1 2", "1 2"),
+            ("S", ":=", "late 1 2", "late 1 2"),
+            ("R", "=", "$(B) $(X)", "late 1 2"),
+            ("D", "=", "default", "default"),
+            ("B", "=", "late", "late"),
+        ];
+
+        for (i, (name, op, value, resolved)) in cases.into_iter().enumerate() {
+            assert_eq!(m.variables[name], Value::new(op, value), "case {:02}", i);
+            let reference = VarStr(format!("$({})", name));
+            assert_eq!(m.resolve_vars(&reference), resolved, "case {:02}", i);
+        }
+    }
+
+    #[test]
+    fn test_undefined_variables() {
+        let m = Makefile::from_terms(
+            &mut IDGen::new("task"),
+            &mut HashSet::new(),
+            PathBuf::from("Makefile"),
+            Vec::new(),
+            &Walker::default(),
+        );
+        let cases = [
+            ("${UNDEFINED}/x", "/x"),
+            ("$(UNDEFINED)/x", "/x"),
+            ("a${UNDEFINED}b$(UNDEFINED)c", "abc"),
+            ("${UNDEFINED}", ""),
+        ];
+
+        for (i, (input, expected)) in cases.into_iter().enumerate() {
+            let result = m.resolve_vars(&VarStr::from(input));
+            assert_eq!(result, expected, "case {:02}", i);
+        }
+    }
+
+    #[test]
+    fn test_use_env() {
+        let env = HashMap::from([
+            ("MAKEDOT_TEST_ENV".to_string(), "env".to_string()),
+            ("MAKEDOT_TEST_DEFINED".to_string(), "env".to_string()),
+        ]);
+        let data = "MAKEDOT_TEST_DEFINED = makefile\n";
+        let cases = [
+            (true, "${MAKEDOT_TEST_ENV}/x", "env/x"),
+            (true, "$(MAKEDOT_TEST_DEFINED)", "makefile"),
+            (true, "$(MAKEDOT_TEST_UNSET)/x", "/x"),
+            (false, "${MAKEDOT_TEST_ENV}/x", "/x"),
+            (false, "$(MAKEDOT_TEST_DEFINED)", "makefile"),
+        ];
+
+        for (i, (use_env, input, expected)) in cases.into_iter().enumerate() {
+            let m = Makefile::from_terms(
+                &mut IDGen::new("task"),
+                &mut HashSet::new(),
+                PathBuf::from("Makefile"),
+                parser::Makefile::parse(data).unwrap(),
+                &Walker::default().env(match use_env {
+                    true => env.clone(),
+                    false => HashMap::new(),
+                }),
+            );
+            let result = m.resolve_vars(&VarStr::from(input));
+            assert_eq!(result, expected, "case {:02}", i);
+        }
+    }
+
+    #[test]
+    fn test_resolved_variables() {
+        let root = fixture(
+            "resolved_variables",
+            &[("Makefile", "CC = gcc\nCMD = $(CC) -O2\nall:\n")],
+        );
+        let m = Makefile::open(root.join("Makefile")).unwrap();
+        let vars = m.resolved_variables();
+        let cmd = vars.iter().find(|(name, _, _)| *name == "CMD").unwrap();
+        assert_eq!(cmd.1, "$(CC) -O2");
+        assert_eq!(cmd.2, "gcc -O2");
+        let names = vars.iter().map(|(name, _, _)| *name).collect::<Vec<&str>>();
+        assert_eq!(names, vec!["CC", "CMD", "MAKEFILE_LIST"]);
+    }
+
+    #[test]
+    fn test_warn_once() {
+        let root = fixture(
+            "warn_once",
+            &[("Makefile", "LOOP = $(LOOP)\nall: $(LOOP)\n\techo $(LOOP)\n")],
+        );
+        let m = Makefile::open(root.join("Makefile")).unwrap();
+        m.resolve_vars(&VarStr::from("$(LOOP)"));
+        m.resolved_variables();
+        assert_eq!(
+            m.warned.borrow().iter().collect::<Vec<&String>>(),
+            vec!["Recursive variable \"LOOP\" references itself"]
+        );
+    }
+}
@@ -1,51 +1,49 @@
 use nom::{
     branch::alt,
-    bytes::complete::{is_a, is_not, tag, take_until},
+    bytes::complete::{is_a, is_not, tag},
     character::complete::{alphanumeric1, char, none_of, one_of},
-    combinator::{eof, opt, recognize, value},
-    error::{context, ParseError, VerboseError},
-    multi::{many0, many0_count, many1_count, many_till},
-    sequence::{delimited, pair, preceded, terminated, tuple},
+    combinator::{eof, not, opt, recognize, value},
+    error::{context, VerboseError},
+    multi::{many0, many0_count, many1, many1_count},
+    sequence::{delimited, pair, preceded, separated_pair, terminated, tuple},
     Finish, Parser,
 };
 
-use crate::ast::{self, Task, Term, Variable};
+use std::ops::Range;
+
+use crate::ast::{self, Conditional, Export, Include, Recipe, Task, Term, Variable};
 
 pub type ParseErr<'a> = VerboseError<&'a str>;
 type ParseResult<'a, O> = nom::IResult<&'a str, O, ParseErr<'a>>;
 
-fn enl(input: &str) -> ParseResult<()> {
-    value((), pair(char('\\'), nl)).parse(input)
+/// Parser state that earlier terms can change for the ones after them.
+#[derive(Debug, Clone, Copy)]
+struct Config {
+    /// Character starting recipe lines, set with `.RECIPEPREFIX`.
+    recipe_prefix: char,
 }
 
-fn hspace0<'a>(tab: bool) -> impl Parser<&'a str, (), ParseErr<'a>> {
-    macro_rules! hst {
-        () => {};
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            recipe_prefix: '\t',
+        }
     }
-    let hst = value(
-        (),
-        many0_count(alt((value((), enl), value((), one_of(" \t"))))),
-    );
+}
 
-    let hsnt = value(
-        (),
-        many0_count(alt((value((), enl), value((), one_of(" \t"))))),
-    );
-    match tab {
-        true => hst,
-        false => hsnt,
-    }
+fn enl(input: &str) -> ParseResult<'_, ()> {
+    value((), pair(char('\\'), nl)).parse(input)
 }
 
-fn hspace1<'a>(tab: bool) -> impl Parser<&'a str, (), ParseErr<'a>> {
+fn hspace0<'a>(tab: bool) -> impl Parser<&'a str, (), ParseErr<'a>> {
     let hst = value(
         (),
-        many1_count(alt((value((), enl), value((), one_of(" \t"))))),
+        many0_count(alt((value((), enl), value((), one_of(" \t"))))),
     );
 
     let hsnt = value(
         (),
-        many1_count(alt((value((), enl), value((), one_of(" \t"))))),
+        many0_count(alt((value((), enl), value((), one_of(" \t"))))),
     );
     match tab {
         true => hst,
@@ -60,14 +58,11 @@ where
     terminated(inner, hspace0(true))
 }
 
-fn ws1<'a, F, O>(inner: F) -> impl Parser<&'a str, O, ParseErr<'a>>
-where
-    F: Parser<&'a str, O, ParseErr<'a>>,
-{
-    terminated(inner, hspace1(true))
+fn keyword<'a>(word: &'static str) -> impl Parser<&'a str, &'a str, ParseErr<'a>> {
+    terminated(tag(word), not(none_of(" \t\r\n(")))
 }
 
-fn comment<'a>(input: &'a str) -> ParseResult<()> {
+fn comment(input: &str) -> ParseResult<'_, ()> {
     context(
         "comment",
         value(
@@ -81,19 +76,45 @@ fn comment<'a>(input: &'a str) -> ParseResult<()> {
     .parse(input)
 }
 
-fn identifier(input: &str) -> ParseResult<&str> {
-    let var_start = tag("$(");
-    let var_end = char(')');
-    let var = recognize(tuple((var_start, is_not(")"), var_end)));
-    let idnt = recognize(many1_count(alt((is_a("._-"), alphanumeric1))));
-    context("identifier", alt((var, idnt))).parse(input)
+/// Variable reference, possibly with nested references inside the name.
+fn reference(input: &str) -> ParseResult<'_, &str> {
+    let paren = delimited(
+        tag("$("),
+        many0_count(alt((reference, is_not("$()"), tag("$")))),
+        char(')'),
+    );
+    let brace = delimited(
+        tag("${"),
+        many0_count(alt((reference, is_not("${}"), tag("$")))),
+        char('}'),
+    );
+    context("reference", recognize(alt((paren, brace)))).parse(input)
+}
+
+fn identifier(input: &str) -> ParseResult<'_, &str> {
+    let idnt = recognize(many1_count(alt((
+        tag("$$"),
+        reference,
+        is_a("._-%"),
+        alphanumeric1,
+    ))));
+    context("identifier", idnt).parse(input)
 }
 
-fn eq(input: &str) -> ParseResult<&str> {
-    context("=/?=", alt((tag("="), tag("?=")))).parse(input)
+fn eq(input: &str) -> ParseResult<'_, &str> {
+    // Longest first, so `=` doesn't cut the others short.
+    let ops = alt((
+        tag("::="),
+        tag(":="),
+        tag("?="),
+        tag("+="),
+        tag("!="),
+        tag("="),
+    ));
+    context("assignment operator", ops).parse(input)
 }
 
-fn rest(input: &str) -> ParseResult<&str> {
+fn rest(input: &str) -> ParseResult<'_, &str> {
     context(
         "rest of line",
         recognize(many0_count(alt((
@@ -104,7 +125,7 @@ fn rest(input: &str) -> ParseResult<&str> {
     .parse(input)
 }
 
-fn var(input: &str) -> ParseResult<(&str, &str, &str)> {
+fn var(input: &str) -> ParseResult<'_, (&str, &str, &str)> {
     context(
         "variable",
         tuple((ws0(identifier), ws0(eq), ws0(rest), opt(comment), eol)),
@@ -113,78 +134,327 @@ fn var(input: &str) -> ParseResult<(&str, &str, &str)> {
     .parse(input)
 }
 
-fn include(input: &str) -> ParseResult<&str> {
-    context("include", tuple((tag("include"), rest, opt(comment), eol)))
-        .map(|(_, file, _, _)| file)
-        .parse(input)
+fn include(input: &str) -> ParseResult<'_, Include<'_>> {
+    // Spaces inside references, e.g. `$(addsuffix .mk, a b)`, don't split.
+    let file = recognize(many1_count(alt((
+        reference,
+        is_not(" \t\r\n#$\\"),
+        tag("$"),
+    ))));
+    context(
+        "include",
+        terminated(
+            pair(
+                ws0(alt((
+                    keyword("include"),
+                    keyword("-include"),
+                    keyword("sinclude"),
+                ))),
+                many0(ws0(file)),
+            ),
+            opt(comment).and(eol),
+        ),
+    )
+    .map(|(directive, files)| Include {
+        files,
+        optional: directive != "include",
+    })
+    .parse(input)
 }
 
-fn define(input: &str) -> ParseResult<(&str, &str, &str)> {
-    let start = tag("define");
-    let end = "endef";
-    context("define", tuple((ws0(start), take_until(end), tag(end)))).parse(input)
+fn define(input: &str) -> ParseResult<'_, (&str, &str, &str)> {
+    let line = pair(opt(is_not("\r\n")), nl);
+    let body = recognize(many0_count(preceded(not(endef), line)));
+    context(
+        "define",
+        tuple((
+            ws0(keyword("define")),
+            ws0(identifier),
+            opt(ws0(eq)).map(|op| op.unwrap_or("=")),
+            preceded(opt(comment).and(eol), body),
+            endef,
+        )),
+    )
+    .map(|(_, name, op, body, _)| {
+        let body = body.strip_suffix('\n').unwrap_or(body);
+        (name, op, body.strip_suffix('\r').unwrap_or(body))
+    })
+    .parse(input)
+}
+
+fn endef(input: &str) -> ParseResult<'_, ()> {
+    value(
+        (),
+        tuple((hspace0(true), keyword("endef"), opt(is_not("\r\n")), eol)),
+    )
+    .parse(input)
 }
 
-fn task(input: &str) -> ParseResult<(&str, Vec<&str>, Vec<&str>)> {
+/// Targets, separator (`:` or `::`), target pattern, normal and order-only
+/// prerequisites, and recipe.
+type Rule<'a> = (
+    Vec<&'a str>,
+    &'a str,
+    Option<&'a str>,
+    (Vec<&'a str>, Vec<&'a str>),
+    Vec<Recipe<'a>>,
+);
+
+fn task(config: Config, input: &str) -> ParseResult<'_, Rule<'_>> {
     context(
         "task",
         tuple((
-            // task name
-            ws0(identifier),
-            ws0(char(':')),
-            // task dependencies
-            many_till(ws0(identifier), opt(comment).and(eol)).map(|(v, _)| v),
+            // task names
+            many1(ws0(identifier)),
+            ws0(alt((tag("::"), tag(":")))),
+            // target pattern of a static pattern rule
+            opt(terminated(ws0(identifier), ws0(char(':')))),
+            // task dependencies, order-only ones after `|`
+            terminated(
+                pair(
+                    many0(ws0(identifier)),
+                    opt(preceded(ws0(char('|')), many0(ws0(identifier))))
+                        .map(Option::unwrap_or_default),
+                ),
+                opt(comment).and(eol),
+            ),
             // task commands
-            many0(alt((
-                delimited(char('\t'), rest, opt(comment).and(eol)).map(Some),
-                value(None, comment.and(eol)),
-            )))
-            .map(|v| {
-                v.into_iter()
-                    .filter_map(|v| match v {
-                        Some(v) if !v.is_empty() => Some(v),
-                        _ => None,
-                    })
-                    .collect()
-            }),
+            |i| recipe(config, i),
+        )),
+    )
+    .parse(input)
+}
+
+/// Assignment with optional `export`, `unexport` or `override` keywords.
+fn modified_var(input: &str) -> ParseResult<'_, Variable<'_>> {
+    let modifier = alt((keyword("export"), keyword("unexport"), keyword("override")));
+    // A variable may itself be called `export`, so try a plain assignment first.
+    let plain = var.map(|var| (vec![], var));
+    alt((plain, pair(many1(ws0(modifier)), var)))
+        .map(|(modifiers, (name, op, value))| Variable {
+            modifiers,
+            name,
+            op,
+            value,
+        })
+        .parse(input)
+}
+
+/// `export` or `unexport` of variables that are assigned elsewhere.
+fn export(input: &str) -> ParseResult<'_, Export<'_>> {
+    context(
+        "export",
+        tuple((
+            ws0(alt((keyword("export"), keyword("unexport")))),
+            many0(ws0(identifier)),
+            opt(comment),
+            eol,
         )),
     )
-    .map(|(name, _, deps, cmds)| (name, deps, cmds))
+    .map(|(directive, names, _, _)| Export {
+        names,
+        export: directive == "export",
+    })
     .parse(input)
 }
 
-fn conditional(input: &str) -> ParseResult<(&str, &str, &str)> {
-    let starts = alt((tag("ifeq"), tag("ifneq"), tag("ifdef"), tag("ifndef")));
-    let end = "endif";
+/// Target-specific variable assignment, e.g. `debug: CFLAGS += -g`.
+fn target_var(input: &str) -> ParseResult<'_, Task<'_>> {
     context(
+        "target-specific variable",
+        tuple((
+            many1(ws0(identifier)),
+            ws0(alt((tag("::"), tag(":")))),
+            modified_var,
+        )),
+    )
+    .map(|(names, separator, variable)| Task {
+        names,
+        double_colon: separator == "::",
+        target_pattern: None,
+        dependencies: vec![],
+        order_only: vec![],
+        commands: vec![],
+        local_vars: vec![variable],
+        line: 0,
+    })
+    .parse(input)
+}
+
+fn recipe(config: Config, input: &str) -> ParseResult<'_, Vec<Recipe<'_>>> {
+    many0(|i| recipe_line(config, i))
+        .map(|lines| lines.into_iter().flatten().collect())
+        .parse(input)
+}
+
+fn recipe_line(config: Config, input: &str) -> ParseResult<'_, Option<Recipe<'_>>> {
+    // Unlike a makefile comment, one inside a recipe line ends with the line.
+    // A trailing `\` continues the recipe, so the next line is still parsed.
+    let recipe_comment = pair(char('#'), opt(is_not("\r\n")));
+    context(
+        "recipe line",
+        alt((
+            delimited(
+                char(config.recipe_prefix),
+                rest,
+                opt(recipe_comment).and(eol),
+            )
+            .map(|cmd| (!cmd.is_empty()).then_some(Recipe::Command(cmd))),
+            comment.and(eol).map(|_| None),
+            (|i| recipe_conditional(config, i)).map(|c| Some(Recipe::Conditional(c))),
+        )),
+    )
+    .parse(input)
+}
+
+/// Conditional wrapping part of a recipe, up to and including its `endif`
+/// line. Which branch is taken is decided along with the rest of the
+/// makefile's conditionals.
+fn recipe_conditional(config: Config, input: &str) -> ParseResult<'_, Conditional<'_, Recipe<'_>>> {
+    let starts = alt((
+        keyword("ifeq"),
+        keyword("ifneq"),
+        keyword("ifdef"),
+        keyword("ifndef"),
+    ));
+    let (input, (kind, condition)) = context(
+        "recipe conditional",
+        pair(starts, terminated(rest, opt(comment).and(eol))),
+    )
+    .parse(input)?;
+    let (input, then) = recipe(config, input)?;
+
+    let endif = || tuple((keyword("endif"), rest, opt(comment), eol));
+    let else_if = preceded(ws0(keyword("else")), |i| recipe_conditional(config, i))
+        .map(|c| vec![Recipe::Conditional(c)]);
+    let otherwise = preceded(
+        tuple((keyword("else"), rest, opt(comment), eol)),
+        terminated(|i| recipe(config, i), endif()),
+    );
+    let (input, otherwise) = alt((else_if, otherwise, endif().map(|_| vec![]))).parse(input)?;
+
+    let conditional = Conditional {
+        kind,
+        operands: operands(kind, condition.trim()),
+        then,
+        otherwise,
+    };
+    Ok((input, conditional))
+}
+
+/// Conditional around whole terms, up to and including its `endif` line.
+fn conditional(config: Config, input: &str) -> ParseResult<'_, Conditional<'_>> {
+    let starts = alt((
+        keyword("ifeq"),
+        keyword("ifneq"),
+        keyword("ifdef"),
+        keyword("ifndef"),
+    ));
+    let (input, (kind, condition)) = context(
         "conditional",
-        tuple((ws0(starts), take_until(end), tag(end))),
+        pair(ws0(starts), terminated(rest, opt(comment).and(eol))),
     )
+    .parse(input)?;
+    let (input, then) = branch(config, input)?;
+
+    // `else ifeq ...` continues the chain, sharing the final `endif`.
+    let else_if = preceded(ws0(keyword("else")), |i| conditional(config, i))
+        .map(|c| vec![Term::Conditional(c)]);
+    let otherwise = preceded(
+        tuple((ws0(keyword("else")), opt(comment), eol)),
+        terminated(|i| branch(config, i), endif),
+    );
+    let (input, otherwise) = alt((else_if, otherwise, endif.map(|_| vec![]))).parse(input)?;
+
+    let conditional = Conditional {
+        kind,
+        operands: operands(kind, condition.trim()),
+        then,
+        otherwise,
+    };
+    Ok((input, conditional))
+}
+
+fn endif(input: &str) -> ParseResult<'_, ()> {
+    value((), tuple((ws0(keyword("endif")), opt(comment), eol))).parse(input)
+}
+
+/// Terms up to the `else` or `endif` closing a conditional branch.
+fn branch(config: Config, input: &str) -> ParseResult<'_, Vec<Term<'_>>> {
+    let end = ws0(alt((keyword("else"), keyword("endif"))));
+    many0(preceded(
+        pair(hspace0(true), not(end)),
+        terminated(|i| term(config, i), hspace0(true)),
+    ))
     .parse(input)
 }
 
-fn term(input: &str) -> ParseResult<Term> {
-    let var = var.map(|(name, op, value)| Term::Variable(Variable { name, op, value }));
+/// Splits a condition into its operands, either `(a,b)`, `"a" "b"` or
+/// `'a' 'b'` for `ifeq`/`ifneq`. Anything else is kept whole.
+fn operands<'a>(kind: &str, condition: &'a str) -> Vec<&'a str> {
+    if !matches!(kind, "ifeq" | "ifneq") {
+        return vec![condition];
+    }
+    let arg = || recognize(many0_count(alt((reference, is_not("$(),")))));
+    let paren = delimited(
+        char('('),
+        pair(arg(), preceded(char(','), arg())),
+        char(')'),
+    );
+    let quoted = || {
+        alt((
+            delimited(char('"'), recognize(opt(is_not("\""))), char('"')),
+            delimited(char('\''), recognize(opt(is_not("'"))), char('\'')),
+        ))
+    };
+    let quoted = separated_pair(quoted(), hspace0(true), quoted());
+    match terminated(alt((paren, quoted)), eof::<_, ParseErr>).parse(condition) {
+        Ok((_, (a, b))) => vec![a.trim(), b.trim()],
+        Err(_) => vec![condition],
+    }
+}
+
+fn term(config: Config, input: &str) -> ParseResult<'_, Term<'_>> {
+    let var = modified_var.map(Term::Variable);
+    let export = export.map(Term::Export);
     let comment = comment.and(eol).map(|_| Term::Empty);
-    let task = task.map(|(name, dependencies, commands)| {
-        Term::Task(Task {
-            name,
-            dependencies,
-            commands,
-        })
-    });
-    let conditional = conditional.map(|_| Term::Unimplemented("conditional"));
-    let include = include.map(|_| Term::Unimplemented("include"));
+    let task = (|i| task(config, i)).map(
+        |(names, separator, target_pattern, (dependencies, order_only), commands)| {
+            Term::Task(Task {
+                names,
+                double_colon: separator == "::",
+                target_pattern,
+                dependencies,
+                order_only,
+                commands,
+                local_vars: vec![],
+                line: 0,
+            })
+        },
+    );
+    let target_var = target_var.map(Term::Task);
+    let conditional = (|i| conditional(config, i)).map(Term::Conditional);
+    let include = include.map(Term::Include);
     let empty = pair(hspace0(true), eol).map(|_| Term::Empty);
-    let define = define.map(|_| Term::Unimplemented("define"));
+    let define = define.map(|(name, op, body)| Term::Define { name, op, body });
     context(
         "term",
-        alt((empty, define, include, conditional, var, comment, task)),
+        alt((
+            empty,
+            define,
+            include,
+            conditional,
+            var,
+            export,
+            comment,
+            target_var,
+            task,
+        )),
     )
     .parse(input)
 }
 
-fn eol(input: &str) -> ParseResult<()> {
+fn eol(input: &str) -> ParseResult<'_, ()> {
     if input.is_empty() {
         return Ok((input, ()));
     }
@@ -192,21 +462,74 @@ fn eol(input: &str) -> ParseResult<()> {
 }
 
 #[cfg(target_family = "windows")]
-fn nl(input: &str) -> ParseResult<()> {
+fn nl(input: &str) -> ParseResult<'_, ()> {
     value((), tag("\r\n"))(input)
 }
 
 #[cfg(not(target_family = "windows"))]
-fn nl(input: &str) -> ParseResult<()> {
+fn nl(input: &str) -> ParseResult<'_, ()> {
     value((), tag("\n"))(input)
 }
 
+/// Sets the line of tasks inside conditionals from where their name lies
+/// in `input`.
+fn nested_lines(input: &str, terms: &mut [Term]) {
+    for term in terms {
+        match term {
+            Term::Task(task) => {
+                let offset = task.names[0].as_ptr() as usize - input.as_ptr() as usize;
+                task.line = 1 + input[..offset].matches('\n').count();
+            }
+            Term::Conditional(c) => {
+                nested_lines(input, &mut c.then);
+                nested_lines(input, &mut c.otherwise);
+            }
+            _ => (),
+        }
+    }
+}
+
 pub struct Makefile;
+
+impl Makefile {
+    /// Parses `input` like [`ast::Parse::parse`], along with the byte range
+    /// each term was read from, including its line ending.
+    pub fn parse_spanned(input: &str) -> Result<Vec<(Range<usize>, Term<'_>)>, ParseErr<'_>> {
+        let mut terms = Vec::new();
+        let (mut rest, _) = hspace0(true).parse(input).finish()?;
+        let mut line = 1 + input[..input.len() - rest.len()].matches('\n').count();
+        let mut config = Config::default();
+        while eof::<_, ParseErr>(rest).is_err() {
+            let start = input.len() - rest.len();
+            let (next, mut term) = term(config, rest).finish()?;
+            let end = input.len() - next.len();
+            let (next, _) = hspace0(true).parse(next).finish()?;
+            match &mut term {
+                Term::Task(task) => task.line = line,
+                Term::Conditional(c) => {
+                    nested_lines(input, &mut c.then);
+                    nested_lines(input, &mut c.otherwise);
+                }
+                // Only applies to the recipes following it.
+                Term::Variable(v) if v.name == ".RECIPEPREFIX" => {
+                    config.recipe_prefix = v.value.chars().next().unwrap_or('\t');
+                }
+                _ => (),
+            }
+            line += rest[..rest.len() - next.len()].matches('\n').count();
+            terms.push((start..end, term));
+            rest = next;
+        }
+        Ok(terms)
+    }
+}
+
 impl<'a> ast::Parse<'a> for Makefile {
     type Error = ParseErr<'a>;
 
     fn parse(input: &'a str) -> Result<Vec<Term<'a>>, Self::Error> {
-        many_till(ws0(term), eof)(input).finish().map(|v| v.1 .0)
+        let terms = Self::parse_spanned(input)?;
+        Ok(terms.into_iter().map(|(_, term)| term).collect())
     }
 }
 
@@ -214,6 +537,8 @@ impl<'a> ast::Parse<'a> for Makefile {
 mod test {
     use nom::{error::convert_error, Finish};
 
+    use crate::ast::{Conditional, Include, Parse as _, Recipe, Term};
+
     #[test]
     fn test_comment() {
         let cases = [
@@ -235,10 +560,41 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_identifier() {
+        let cases = [
+            ("foo", Ok(("", "foo"))),
+            ("foo.o: bar", Ok((": bar", "foo.o"))),
+            ("$(CC)", Ok(("", "$(CC)"))),
+            ("${CC}", Ok(("", "${CC}"))),
+            ("$(CC_$(ARCH)) x", Ok((" x", "$(CC_$(ARCH))"))),
+            ("CC_$(ARCH)", Ok(("", "CC_$(ARCH)"))),
+            (
+                "$(dir $(lastword $(A)))",
+                Ok(("", "$(dir $(lastword $(A)))")),
+            ),
+        ];
+
+        for (i, (input, expected)) in cases.into_iter().enumerate() {
+            let result = super::identifier(input).finish();
+            assert_eq!(result, expected, "case {:02}, input: {:?}", i, input);
+        }
+    }
+
     #[test]
     fn test_vars() {
         let cases = [
             ("foo=bar", Ok(("", ("foo", "=", "bar")))),
+            ("foo::=bar", Ok(("", ("foo", "::=", "bar")))),
+            ("foo:=bar", Ok(("", ("foo", ":=", "bar")))),
+            ("foo?=bar", Ok(("", ("foo", "?=", "bar")))),
+            ("foo+=bar", Ok(("", ("foo", "+=", "bar")))),
+            ("foo!=echo bar", Ok(("", ("foo", "!=", "echo bar")))),
+            ("foo := =bar", Ok(("", ("foo", ":=", "=bar")))),
+            ("foo ?= bar", Ok(("", ("foo", "?=", "bar")))),
+            ("foo := bar", Ok(("", ("foo", ":=", "bar")))),
+            ("foo ::= bar", Ok(("", ("foo", "::=", "bar")))),
+            ("foo:= bar", Ok(("", ("foo", ":=", "bar")))),
             ("var = value", Ok(("", ("var", "=", "value")))),
             ("_var=123", Ok(("", ("_var", "=", "123")))),
             ("VAR=Hello World!", Ok(("", ("VAR", "=", "Hello World!")))),
@@ -271,4 +627,421 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn test_recipe_conditional() {
+        let input = "all:\n\techo one\nifeq ($(X),y)\n\techo two\nendif\nnext:\n";
+        let result = super::task(Default::default(), input).finish();
+        let conditional = Conditional {
+            kind: "ifeq",
+            operands: vec!["$(X)", "y"],
+            then: vec![Recipe::Command("echo two")],
+            otherwise: vec![],
+        };
+        assert_eq!(
+            result,
+            Ok((
+                "next:\n",
+                (
+                    vec!["all"],
+                    ":",
+                    None,
+                    (vec![], vec![]),
+                    vec![
+                        Recipe::Command("echo one"),
+                        Recipe::Conditional(conditional)
+                    ]
+                )
+            ))
+        );
+
+        let input = "all:\nifdef A\n\techo a\nelse ifdef B\n\techo b\nelse\n\techo c\nendif\n";
+        let (_, (_, _, _, _, commands)) = super::task(Default::default(), input).unwrap();
+        let [Recipe::Conditional(first)] = &commands[..] else {
+            panic!("{:?}", commands);
+        };
+        assert_eq!(first.then, vec![Recipe::Command("echo a")]);
+        let [Recipe::Conditional(second)] = &first.otherwise[..] else {
+            panic!("{:?}", first.otherwise);
+        };
+        assert_eq!((second.kind, &second.operands[..]), ("ifdef", &["B"][..]));
+        assert_eq!(second.then, vec![Recipe::Command("echo b")]);
+        assert_eq!(second.otherwise, vec![Recipe::Command("echo c")]);
+    }
+
+    #[test]
+    fn test_recipe_tabs() {
+        let cases = [
+            ("all:\n\techo\t\tcolumns\n", "echo\t\tcolumns"),
+            ("all:\n\t\techo a\tb\n", "\techo a\tb"),
+            ("all:\n\tprintf 'a\tb' \t# comment\n", "printf 'a\tb' \t"),
+        ];
+
+        for (i, (input, expected)) in cases.into_iter().enumerate() {
+            let result = super::task(Default::default(), input).finish();
+            assert_eq!(
+                result,
+                Ok((
+                    "",
+                    (
+                        vec!["all"],
+                        ":",
+                        None,
+                        (vec![], vec![]),
+                        vec![Recipe::Command(expected)]
+                    )
+                )),
+                "case {:02}, input: {:?}",
+                i,
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_recipe_comment_continuation() {
+        let cases = [
+            ("all:\n\t# note \\\n\techo b\n", vec!["echo b"]),
+            (
+                "all:\n\techo a # note \\\n\techo b\n",
+                vec!["echo a ", "echo b"],
+            ),
+            // A makefile comment, continued over the next line like make does.
+            ("all:\n# note \\\n\techo b\n\techo c\n", vec!["echo c"]),
+        ];
+
+        for (i, (input, expected)) in cases.into_iter().enumerate() {
+            let result = super::task(Default::default(), input).finish();
+            let expected = expected.into_iter().map(Recipe::Command).collect();
+            assert_eq!(
+                result,
+                Ok(("", (vec!["all"], ":", None, (vec![], vec![]), expected))),
+                "case {:02}, input: {:?}",
+                i,
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_task_continuation() {
+        let cases = [
+            ("foo: \\\n dep1 dep2\n", ("foo", vec!["dep1", "dep2"])),
+            ("foo:\\\n\tdep1 dep2\n", ("foo", vec!["dep1", "dep2"])),
+            ("foo \\\n: dep1 dep2\n", ("foo", vec!["dep1", "dep2"])),
+            ("foo\\\n: dep1 dep2\n", ("foo", vec!["dep1", "dep2"])),
+            (
+                "foo \\\n : \\\n dep1 \\\n dep2\n",
+                ("foo", vec!["dep1", "dep2"]),
+            ),
+        ];
+
+        for (i, (input, (name, deps))) in cases.into_iter().enumerate() {
+            let result = super::task(Default::default(), input).finish();
+            assert_eq!(
+                result,
+                Ok(("", (vec![name], ":", None, (deps, vec![]), vec![]))),
+                "case {:02}, input: {:?}",
+                i,
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_multiple_targets() {
+        let cases = [
+            ("a b c: dep\n", vec!["a", "b", "c"]),
+            ("a \\\n b: dep\n", vec!["a", "b"]),
+            ("$(OUT) log.txt : dep\n", vec!["$(OUT)", "log.txt"]),
+        ];
+
+        for (i, (input, names)) in cases.into_iter().enumerate() {
+            let result = super::task(Default::default(), input).finish();
+            assert_eq!(
+                result,
+                Ok(("", (names, ":", None, (vec!["dep"], vec![]), vec![]))),
+                "case {:02}, input: {:?}",
+                i,
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_double_colon() {
+        let cases = [
+            ("clean:: tmp\n", ("clean", "::", vec!["tmp"])),
+            ("clean ::\n", ("clean", "::", vec![])),
+            ("all: clean\n", ("all", ":", vec!["clean"])),
+        ];
+
+        for (i, (input, (name, separator, deps))) in cases.into_iter().enumerate() {
+            let result = super::task(Default::default(), input).finish();
+            assert_eq!(
+                result,
+                Ok(("", (vec![name], separator, None, (deps, vec![]), vec![]))),
+                "case {:02}, input: {:?}",
+                i,
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_order_only() {
+        let cases = [
+            ("foo: bar | dir\n", (vec!["bar"], vec!["dir"])),
+            ("foo: | dir other # c\n", (vec![], vec!["dir", "other"])),
+            ("foo: bar|dir\n", (vec!["bar"], vec!["dir"])),
+            ("foo: bar |\n", (vec!["bar"], vec![])),
+        ];
+
+        for (i, (input, deps)) in cases.into_iter().enumerate() {
+            let result = super::task(Default::default(), input).finish();
+            assert_eq!(
+                result,
+                Ok(("", (vec!["foo"], ":", None, deps, vec![]))),
+                "case {:02}, input: {:?}",
+                i,
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_static_pattern() {
+        let cases = [
+            ("$(OBJS): %.o: %.c\n", ("$(OBJS)", Some("%.o"), vec!["%.c"])),
+            (
+                "$(OBJS) : %.o : %.c common.h\n",
+                ("$(OBJS)", Some("%.o"), vec!["%.c", "common.h"]),
+            ),
+            ("%.o: %.c\n", ("%.o", None, vec!["%.c"])),
+        ];
+
+        for (i, (input, (name, pattern, deps))) in cases.into_iter().enumerate() {
+            let result = super::task(Default::default(), input).finish();
+            assert_eq!(
+                result,
+                Ok(("", (vec![name], ":", pattern, (deps, vec![]), vec![]))),
+                "case {:02}, input: {:?}",
+                i,
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_operators_without_spaces() {
+        let ops = ["=", ":=", "::=", "?=", "+=", "!="];
+        let names = ["A", "CFLAGS", "obj.d", "my-var_2"];
+        let values = ["1", "-g", "=x", ":x"];
+
+        for (i, op) in ops.into_iter().enumerate() {
+            for name in names {
+                for value in values {
+                    let input = format!("{}{}{}\n", name, op, value);
+                    let terms = super::Makefile::parse(&input).unwrap();
+                    assert!(
+                        matches!(
+                            &terms[..],
+                            [Term::Variable(v)] if (v.name, v.op, v.value) == (name, op, value)
+                        ),
+                        "case {:02}, input: {:?}, got {:?}",
+                        i,
+                        input,
+                        terms
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_simple_assignment() {
+        let input = "CC := gcc\nLD ::= ld\nall: CC\n";
+        let terms = super::Makefile::parse(input).unwrap();
+        assert!(
+            matches!(
+                &terms[..],
+                [
+                    Term::Variable(cc),
+                    Term::Variable(ld),
+                    Term::Task(all),
+                ] if (cc.name, cc.op, cc.value) == ("CC", ":=", "gcc")
+                    && (ld.name, ld.op, ld.value) == ("LD", "::=", "ld")
+                    && (&all.names[..], &all.dependencies[..]) == (&["all"][..], &["CC"][..])
+            ),
+            "{:?}",
+            terms
+        );
+    }
+
+    #[test]
+    fn test_export() {
+        let cases = [
+            ("export A = 1\n", Ok((vec!["export"], "A", "=", "1"))),
+            ("override A := 1\n", Ok((vec!["override"], "A", ":=", "1"))),
+            (
+                "export override A += 1\n",
+                Ok((vec!["export", "override"], "A", "+=", "1")),
+            ),
+            ("unexport A ?= 1\n", Ok((vec!["unexport"], "A", "?=", "1"))),
+            ("export = 1\n", Ok((vec![], "export", "=", "1"))),
+            ("exports = 1\n", Ok((vec![], "exports", "=", "1"))),
+            ("export A\n", Err(vec!["A"])),
+            ("export A $(B) # c\n", Err(vec!["A", "$(B)"])),
+            ("unexport A\n", Err(vec!["A"])),
+            ("export\n", Err(vec![])),
+        ];
+
+        for (i, (input, expected)) in cases.into_iter().enumerate() {
+            let terms = super::Makefile::parse(input).unwrap();
+            match (&terms[..], expected) {
+                ([Term::Variable(v)], Ok(expected)) => assert_eq!(
+                    (v.modifiers.clone(), v.name, v.op, v.value),
+                    expected,
+                    "case {:02}",
+                    i
+                ),
+                ([Term::Export(e)], Err(names)) => {
+                    assert_eq!(e.names, names, "case {:02}", i);
+                    assert_eq!(e.export, input.starts_with("export"), "case {:02}", i);
+                }
+                (terms, _) => panic!("case {:02}, got {:?}", i, terms),
+            }
+        }
+    }
+
+    #[test]
+    fn test_define() {
+        let cases = [
+            ("define X\nendef\n", ("X", "=", "")),
+            ("define X\na\n  b # c\nendef\n", ("X", "=", "a\n  b # c")),
+            (
+                "define X :=\n\n$(Y)\n\nendef # done",
+                ("X", ":=", "\n$(Y)\n"),
+            ),
+            ("define X +=\n\tendefs\n  endef\n", ("X", "+=", "\tendefs")),
+        ];
+
+        for (i, (input, expected)) in cases.into_iter().enumerate() {
+            let result = super::define(input).finish();
+            assert_eq!(
+                result,
+                Ok(("", expected)),
+                "case {:02}, input: {:?}",
+                i,
+                input
+            );
+        }
+        assert!(super::define("defined = a\n").is_err());
+        assert!(super::define("define X\na\n").is_err());
+    }
+
+    #[test]
+    fn test_include() {
+        let cases = [
+            ("include a.mk\n", vec!["a.mk"], false),
+            ("-include a.mk\n", vec!["a.mk"], true),
+            ("sinclude a.mk b.mk\n", vec!["a.mk", "b.mk"], true),
+            (
+                "include a.mk $(DIR)/b.mk # rules\n",
+                vec!["a.mk", "$(DIR)/b.mk"],
+                false,
+            ),
+            ("include \\\n\ta.mk\n", vec!["a.mk"], false),
+            (
+                "include $(addsuffix .mk, a b)",
+                vec!["$(addsuffix .mk, a b)"],
+                false,
+            ),
+        ];
+
+        for (i, (input, files, optional)) in cases.into_iter().enumerate() {
+            let result = super::include(input).finish();
+            assert_eq!(
+                result,
+                Ok(("", Include { files, optional })),
+                "case {:02}, input: {:?}",
+                i,
+                input
+            );
+        }
+        assert!(super::include("includes = a\n").is_err());
+    }
+
+    #[test]
+    fn test_nested_conditional() {
+        let input =
+            "ifeq (a,b)\nifdef X\n  foo: bar\nendif # inner\nbaz: qux\nelse\nqux:\nendif\nrest:\n";
+        let terms = super::Makefile::parse(input).unwrap();
+        let [Term::Conditional(outer), Term::Task(rest)] = &terms[..] else {
+            panic!("{:?}", terms);
+        };
+        assert_eq!((outer.kind, &outer.operands[..]), ("ifeq", &["a", "b"][..]));
+        assert_eq!(rest.line, 9);
+        let [Term::Conditional(inner), Term::Task(baz)] = &outer.then[..] else {
+            panic!("{:?}", outer.then);
+        };
+        assert_eq!((&baz.names[..], baz.line), (&["baz"][..], 5));
+        assert!(matches!(&outer.otherwise[..], [Term::Task(t)] if t.names == ["qux"]));
+        assert_eq!((inner.kind, &inner.operands[..]), ("ifdef", &["X"][..]));
+        assert!(
+            matches!(&inner.then[..], [Term::Task(t)] if (&t.names[..], t.line) == (&["foo"][..], 3))
+        );
+        assert!(inner.otherwise.is_empty());
+    }
+
+    #[test]
+    fn test_conditional_chain() {
+        let input = "ifeq \"$(A)\" 'x'\na:\nelse ifneq ($(B), y)\nb:\nelse\nc:\nendif\n";
+        let terms = super::Makefile::parse(input).unwrap();
+        let [Term::Conditional(first)] = &terms[..] else {
+            panic!("{:?}", terms);
+        };
+        assert_eq!(&first.operands[..], &["$(A)", "x"]);
+        let [Term::Conditional(second)] = &first.otherwise[..] else {
+            panic!("{:?}", first.otherwise);
+        };
+        assert_eq!(
+            (second.kind, &second.operands[..]),
+            ("ifneq", &["$(B)", "y"][..])
+        );
+        assert!(matches!(&second.then[..], [Term::Task(t)] if t.names == ["b"]));
+        assert!(matches!(&second.otherwise[..], [Term::Task(t)] if t.names == ["c"]));
+    }
+
+    #[test]
+    fn test_recipe_prefix() {
+        let input = "a:\n\techo a\n.RECIPEPREFIX = >\nb:\n>echo b\n";
+        let terms = super::Makefile::parse(input).unwrap();
+        let tasks = terms
+            .iter()
+            .filter_map(|t| match t {
+                Term::Task(t) => Some((t.names[0], &t.commands)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            tasks,
+            vec![
+                ("a", &vec![Recipe::Command("echo a")]),
+                ("b", &vec![Recipe::Command("echo b")])
+            ]
+        );
+    }
+
+    #[test]
+    fn test_spans() {
+        let input = "A = 1\n\n  all: dep\n\techo\nnext:";
+        let terms = super::Makefile::parse_spanned(input).unwrap();
+        let spans = terms
+            .iter()
+            .map(|(span, _)| &input[span.clone()])
+            .collect::<Vec<&str>>();
+        assert_eq!(spans, vec!["A = 1\n", "\n", "all: dep\n\techo\n", "next:"]);
+        assert!(matches!(&terms[2], (span, Term::Task(t)) if *span == (9..24) && t.line == 3));
+    }
 }
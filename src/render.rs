@@ -0,0 +1,1317 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, Write},
+    path::{Component, Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use serde::Serialize;
+
+use crate::{
+    graph::{DependencyGraph, GraphDiff, Node},
+    makefile::{External, IDGen, Makefile, ID},
+};
+
+/// Default attributes of phony targets' nodes.
+pub const PHONY_STYLE: &str = "shape=box, style=dashed";
+
+#[derive(Debug, Default)]
+pub struct Options {
+    /// Number of leading path components dropped from makefile labels.
+    pub label_skip: usize,
+    /// Precede nodes and edges with a comment naming the rule they came from.
+    pub annotate_source: bool,
+    /// Place all tasks without dependencies on the same rank.
+    pub align_leaves: bool,
+    /// Space between ranks, in inches. Scales with the graph when unset.
+    pub ranksep: Option<f64>,
+    /// Label folded edges with the number of folded tasks, not their names.
+    pub compact: bool,
+    /// Group tasks merged in from other files by the file they came from.
+    pub group_origins: bool,
+    /// Leave out the `digraph` wrapper, for embedding into another graph.
+    pub fragment: bool,
+    /// Fill nodes with a color derived from the file defining them.
+    pub color_by_makefile: bool,
+    /// Attributes of phony targets' nodes, [`PHONY_STYLE`] when unset. Empty
+    /// to draw them like file targets.
+    pub phony_style: Option<String>,
+    /// Font for labels, Graphviz's default when unset.
+    pub font_name: Option<String>,
+    /// Font size for labels in points, Graphviz's default when unset.
+    pub font_size: Option<f64>,
+    /// Number of tasks left out to keep the graph small, noted in the output.
+    pub truncated: usize,
+    /// Changes since an earlier version of the makefiles. When set, tasks and
+    /// edges that changed are highlighted and the rest is dimmed.
+    pub changes: Option<GraphDiff>,
+}
+
+impl Options {
+    pub fn label(&self, path: &Path) -> String {
+        let parts = path
+            .components()
+            .filter(|c| matches!(c, Component::Normal(_)))
+            .collect::<Vec<Component>>();
+        // Always keep the file name itself.
+        let skip = self.label_skip.min(parts.len().saturating_sub(1));
+        if skip == 0 {
+            return path.display().to_string();
+        }
+        parts[skip..]
+            .iter()
+            .collect::<PathBuf>()
+            .display()
+            .to_string()
+    }
+
+    /// Small graphs look lost with a wide rank separation, so it grows with
+    /// the number of nodes up to the old fixed value of 3.
+    fn ranksep(&self, nodes: usize) -> f64 {
+        self.ranksep
+            .unwrap_or_else(|| 0.5 + 2.5 * nodes.min(300) as f64 / 300.0)
+    }
+
+    /// Sets the font of graph, node and edge labels, if one was given.
+    fn write_fonts(&self, out: &mut impl Write) -> io::Result<()> {
+        let mut attrs = Vec::new();
+        if let Some(name) = &self.font_name {
+            attrs.push(format!("fontname=\"{}\"", dot_str(name)));
+        }
+        if let Some(size) = self.font_size {
+            attrs.push(format!("fontsize={}", size));
+        }
+        if attrs.is_empty() {
+            return Ok(());
+        }
+        let attrs = attrs.join(", ");
+        for kind in ["graph", "node", "edge"] {
+            writeln!(out, "\t{} [{}]", kind, attrs)?;
+        }
+        Ok(())
+    }
+
+    fn phony_attrs(&self) -> Option<&str> {
+        let style = self.phony_style.as_deref().unwrap_or(PHONY_STYLE);
+        (!style.is_empty()).then_some(style)
+    }
+
+    /// Highlights or dims a node or edge in review mode, see `changes`.
+    fn review_attrs(&self, changed: impl FnOnce(&GraphDiff) -> bool) -> Option<&'static str> {
+        let changes = self.changes.as_ref()?;
+        match changed(changes) {
+            true => Some("color=red, penwidth=2"),
+            false => Some("color=gray70, fontcolor=gray70"),
+        }
+    }
+
+    /// A task changed when it was added or its edges to other tasks did.
+    fn node_attrs(&self, node: &Node) -> Option<&'static str> {
+        self.review_attrs(|c| {
+            c.added_nodes.contains(node)
+                || c.added_edges.iter().any(|(from, _)| from == node)
+                || c.removed_edges.iter().any(|(from, _)| from == node)
+        })
+    }
+
+    fn edge_attrs(&self, graph: &DependencyGraph, from: &str, to: &str) -> Option<&'static str> {
+        let node = |id| {
+            graph
+                .task(id)
+                .map(|(m, t)| (m.file.clone(), t.name.clone()))
+        };
+        let edge = node(from).zip(node(to));
+        self.review_attrs(|c| edge.is_some_and(|edge| c.added_edges.contains(&edge)))
+    }
+
+    fn folded_label(&self, folded: &[String]) -> String {
+        match self.compact {
+            true => format!("+{}", folded.len()),
+            false => folded.join(", "),
+        }
+    }
+
+    fn write_source(
+        &self,
+        out: &mut impl Write,
+        indent: &str,
+        file: &Path,
+        line: usize,
+    ) -> io::Result<()> {
+        if self.annotate_source {
+            writeln!(out, "{}// from {}:{}", indent, self.label(file), line)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn write_dot(
+    out: &mut impl Write,
+    graph: &DependencyGraph,
+    options: &Options,
+) -> io::Result<()> {
+    let mut id = IDGen::new("cluster_");
+    if !options.fragment {
+        let nodes = graph.makefiles.iter().map(|m| m.tasks.len()).sum();
+        writeln!(out, "digraph G {{\n\tranksep={:.2}", options.ranksep(nodes))?;
+        options.write_fonts(out)?;
+    }
+    if options.truncated > 0 {
+        let note = format!("{} more tasks not shown", options.truncated);
+        match options.fragment {
+            true => writeln!(out, "\t// {}", note)?,
+            false => writeln!(out, "\tlabel=\"{}\"\n\tlabelloc=t", note)?,
+        }
+    }
+    for makefile in graph.makefiles.iter() {
+        writeln!(
+            out,
+            "\tsubgraph {} {{\n\t\tlabel=\"{}\"",
+            id.next(),
+            dot_str(&options.label(&makefile.file))
+        )?;
+
+        for (id, task) in &makefile.tasks {
+            options.write_source(out, "\t\t", &makefile.file, task.line)?;
+            let mut attrs = vec![format!("label=\"{}\"", dot_str(&task.name))];
+            if options.color_by_makefile {
+                attrs.push(format!(
+                    "style=filled, fillcolor=\"{}\"",
+                    file_color(&task.origin_file)
+                ));
+            }
+            if task.phony {
+                attrs.extend(options.phony_attrs().map(String::from));
+            }
+            let node = (makefile.file.clone(), task.name.clone());
+            attrs.extend(options.node_attrs(&node).map(String::from));
+            writeln!(out, "\t\t{}{}", id, dot_attrs(attrs))?;
+            for dep in task.dependencies.iter() {
+                let Some(dep_id) = makefile.get_id(dep) else {
+                    eprintln!("Bad dependency: {}", dep);
+                    continue;
+                };
+                options.write_source(out, "\t\t", &makefile.file, task.line)?;
+                let mut attrs = Vec::new();
+                if let Some(folded) = graph.folded_between(id, dep_id) {
+                    attrs.push(format!(
+                        "label=\"{}\"",
+                        dot_str(&options.folded_label(folded))
+                    ));
+                }
+                attrs.extend(options.edge_attrs(graph, id, dep_id).map(String::from));
+                writeln!(out, "\t\t{} -> {}{}", id, dep_id, dot_attrs(attrs))?;
+            }
+            for dep in task.order_only.iter() {
+                match makefile.get_id(dep) {
+                    Some(dep_id) => {
+                        options.write_source(out, "\t\t", &makefile.file, task.line)?;
+                        let mut attrs = vec![String::from("style=dashed")];
+                        attrs.extend(options.edge_attrs(graph, id, dep_id).map(String::from));
+                        writeln!(out, "\t\t{} -> {}{}", id, dep_id, dot_attrs(attrs))?;
+                    }
+                    None => eprintln!("Bad dependency: {}", dep),
+                }
+            }
+        }
+        if options.group_origins {
+            let mut origins: HashMap<&Path, Vec<&ID>> = HashMap::new();
+            for (id, task) in makefile.tasks.iter() {
+                if task.origin_file != makefile.file {
+                    origins.entry(&task.origin_file).or_default().push(id);
+                }
+            }
+            let mut origins = origins.into_iter().collect::<Vec<_>>();
+            origins.sort();
+            for (origin, mut ids) in origins {
+                ids.sort();
+                let ids = ids.iter().map(|id| format!(" {};", id)).collect::<String>();
+                writeln!(
+                    out,
+                    "\t\tsubgraph {} {{ style=dashed; label=\"{}\";{} }}",
+                    id.next(),
+                    dot_str(&options.label(origin)),
+                    ids
+                )?;
+            }
+        }
+        writeln!(out, "\t}}")?;
+    }
+
+    for (from, to) in external_edges(graph) {
+        if let Some((m, task)) = graph.task(from) {
+            options.write_source(out, "\t", &m.file, task.line)?;
+        }
+        let attrs = options.edge_attrs(graph, from, to).map(String::from);
+        writeln!(out, "\t{} -> {}{}", from, to, dot_attrs(attrs))?;
+    }
+    let mut id = IDGen::new("dynamic");
+    for external in graph.dynamic.iter() {
+        let node = id.next();
+        let path = external.path.trim_matches(['"', '\'']);
+        writeln!(out, "\t{}[label=\"{}\", style=dashed]", node, dot_str(path))?;
+        writeln!(out, "\t{} -> {}[style=dashed]", external.id, node)?;
+    }
+    if options.align_leaves {
+        let leaves = graph.leaves();
+        if !leaves.is_empty() {
+            let leaves = leaves
+                .iter()
+                .map(|id| format!(" {};", id))
+                .collect::<String>();
+            writeln!(out, "\t{{ rank=same;{} }}", leaves)?;
+        }
+    }
+    if options.fragment {
+        return Ok(());
+    }
+    writeln!(out, "}}")
+}
+
+/// Writes one node per directory holding a makefile, with edges labelled by
+/// the number of `make` invocations from one directory into another.
+pub fn write_directories(
+    out: &mut impl Write,
+    graph: &DependencyGraph,
+    options: &Options,
+) -> io::Result<()> {
+    let mut id = IDGen::new("dir");
+    let dirs = graph
+        .directories()
+        .into_iter()
+        .map(|d| (d, id.next()))
+        .collect::<HashMap<&Path, ID>>();
+    writeln!(out, "digraph G {{")?;
+    options.write_fonts(out)?;
+    for dir in graph.directories() {
+        writeln!(
+            out,
+            "\t{}[label=\"{}\"]",
+            dirs[dir],
+            dot_str(&options.label(dir))
+        )?;
+    }
+    for (from, to, count) in graph.directory_edges() {
+        let (Some(from), Some(to)) = (dirs.get(from), dirs.get(to)) else {
+            continue;
+        };
+        writeln!(out, "\t{} -> {}[label=\"{}\"]", from, to, count)?;
+    }
+    writeln!(out, "}}")
+}
+
+pub fn write_plantuml(
+    out: &mut impl Write,
+    graph: &DependencyGraph,
+    options: &Options,
+) -> io::Result<()> {
+    writeln!(out, "@startuml")?;
+    for makefile in graph.makefiles.iter() {
+        writeln!(
+            out,
+            "package \"{}\" {{",
+            plantuml_str(&options.label(&makefile.file))
+        )?;
+        for (id, task) in &makefile.tasks {
+            writeln!(
+                out,
+                "\tcomponent \"{}\" as {}",
+                plantuml_str(&task.name),
+                id
+            )?;
+        }
+        writeln!(out, "}}")?;
+    }
+
+    for makefile in graph.makefiles.iter() {
+        for (id, task) in &makefile.tasks {
+            for dep in task.dependencies.iter() {
+                match makefile.get_id(dep) {
+                    Some(dep_id) => match graph.folded_between(id, dep_id) {
+                        Some(folded) => {
+                            let label = options.folded_label(folded);
+                            writeln!(out, "{} --> {} : {}", id, dep_id, label)?
+                        }
+                        None => writeln!(out, "{} --> {}", id, dep_id)?,
+                    },
+                    None => eprintln!("Bad dependency: {}", dep),
+                }
+            }
+        }
+    }
+    for (from, to) in external_edges(graph) {
+        writeln!(out, "{} ..> {}", from, to)?;
+    }
+    writeln!(out, "@enduml")
+}
+
+/// Writes the adjacency matrix as CSV, with task names as row and column
+/// headers. Each cell adds up the kinds of edges from the row's task to the
+/// column's: 1 for a prerequisite, 2 for an order-only prerequisite and 4
+/// for a make invocation. Tasks are in makefile, then definition, order.
+pub fn write_matrix(out: &mut impl Write, graph: &DependencyGraph) -> io::Result<()> {
+    let mut tasks = Vec::new();
+    for makefile in graph.makefiles.iter() {
+        let mut ids = makefile.tasks.iter().collect::<Vec<_>>();
+        ids.sort_by(|(a_id, a), (b_id, b)| (a.line, a_id).cmp(&(b.line, b_id)));
+        tasks.extend(ids.into_iter().map(|(id, task)| (makefile, id, task)));
+    }
+    let index = tasks
+        .iter()
+        .enumerate()
+        .map(|(i, (_, id, _))| (id.as_str(), i))
+        .collect::<HashMap<&str, usize>>();
+
+    let mut cells = vec![vec![0u8; tasks.len()]; tasks.len()];
+    for (i, (makefile, _, task)) in tasks.iter().enumerate() {
+        let kinds = [(&task.dependencies, 1), (&task.order_only, 2)];
+        for (deps, kind) in kinds {
+            for dep in deps.iter().filter_map(|d| makefile.get_id(d)) {
+                cells[i][index[dep.as_str()]] |= kind;
+            }
+        }
+    }
+    for (from, to) in external_edges(graph) {
+        if let (Some(from), Some(to)) = (index.get(from), index.get(to)) {
+            cells[*from][*to] |= 4;
+        }
+    }
+
+    let header = tasks
+        .iter()
+        .map(|(_, _, task)| csv_str(&task.name))
+        .collect::<Vec<String>>();
+    writeln!(out, ",{}", header.join(","))?;
+    for (name, row) in header.iter().zip(cells) {
+        let row = row.iter().map(|c| c.to_string()).collect::<Vec<String>>();
+        writeln!(out, "{},{}", name, row.join(","))?;
+    }
+    Ok(())
+}
+
+/// Writes the makefiles, their tasks and the calls into other makefiles as
+/// JSON. Tasks keep their ids, which calls refer to by the `id` of the calling
+/// task; prerequisites and called tasks are names within their makefile.
+pub fn write_json(out: &mut impl Write, graph: &DependencyGraph) -> io::Result<()> {
+    #[derive(Serialize)]
+    struct Graph<'g> {
+        makefiles: &'g [Makefile],
+        externals: Vec<&'g External<PathBuf>>,
+    }
+    let mut externals = graph.externals.iter().collect::<Vec<_>>();
+    externals.sort();
+    let graph = Graph {
+        makefiles: &graph.makefiles,
+        externals,
+    };
+    serde_json::to_writer_pretty(&mut *out, &graph)?;
+    writeln!(out)
+}
+
+/// Writes a standalone HTML page with the graph and a collapsible section
+/// listing the tasks of each makefile. The graph is inlined as SVG when
+/// Graphviz's `dot` is installed, and as DOT source otherwise.
+pub fn write_html(
+    out: &mut impl Write,
+    graph: &DependencyGraph,
+    options: &Options,
+) -> io::Result<()> {
+    let mut dot = Vec::new();
+    write_dot(&mut dot, graph, options)?;
+    let dot = String::from_utf8_lossy(&dot);
+
+    writeln!(out, "<!DOCTYPE html>")?;
+    writeln!(out, "<html>\n<head>\n<meta charset=\"utf-8\">")?;
+    writeln!(out, "<title>makedot</title>\n</head>\n<body>")?;
+    match render_svg(&dot) {
+        Some(svg) => writeln!(out, "<figure>\n{}\n</figure>", svg.trim())?,
+        None => writeln!(out, "<pre class=\"dot\">{}</pre>", html_str(&dot))?,
+    }
+    for makefile in graph.makefiles.iter() {
+        writeln!(
+            out,
+            "<details open>\n<summary>{}</summary>\n<ul>",
+            html_str(&options.label(&makefile.file))
+        )?;
+        let mut names = makefile
+            .tasks
+            .values()
+            .map(|t| t.name.as_str())
+            .collect::<Vec<&str>>();
+        names.sort();
+        for name in names {
+            writeln!(out, "<li>{}</li>", html_str(name))?;
+        }
+        writeln!(out, "</ul>\n</details>")?;
+    }
+    writeln!(out, "</body>\n</html>")
+}
+
+/// Renders DOT source with Graphviz, if it's installed.
+fn render_svg(dot: &str) -> Option<String> {
+    let mut child = Command::new("dot")
+        .arg("-Tsvg")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .ok()?;
+    child.stdin.take()?.write_all(dot.as_bytes()).ok()?;
+    let output = child.wait_with_output().ok()?;
+    let svg = String::from_utf8(output.stdout).ok()?;
+    // Drop the XML prolog, it isn't allowed inside HTML.
+    let start = svg.find("<svg")?;
+    output.status.success().then(|| svg[start..].to_string())
+}
+
+fn html_str(str: &str) -> String {
+    str.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Writes the dependencies of `target` as an indented tree. Tasks whose
+/// dependencies were already listed are marked with `(*)`.
+pub fn write_tree(out: &mut impl Write, graph: &DependencyGraph, target: &str) -> io::Result<()> {
+    let Some(id) = graph.find_task(target) else {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Task not found: {}", target),
+        ));
+    };
+    writeln!(out, "{}", target)?;
+    let mut seen = HashSet::from([id]);
+    write_subtree(out, graph, id, "", &mut seen)
+}
+
+fn write_subtree<'a>(
+    out: &mut impl Write,
+    graph: &'a DependencyGraph,
+    id: &ID,
+    prefix: &str,
+    seen: &mut HashSet<&'a ID>,
+) -> io::Result<()> {
+    let deps = graph.dependencies(id);
+    for (i, (name, dep)) in deps.iter().enumerate() {
+        let last = i + 1 == deps.len();
+        let branch = if last { "└── " } else { "├── " };
+        match dep {
+            Some(dep) if !seen.insert(dep) => writeln!(out, "{}{}{} (*)", prefix, branch, name)?,
+            Some(dep) => {
+                writeln!(out, "{}{}{}", prefix, branch, name)?;
+                let indent = if last { "    " } else { "│   " };
+                write_subtree(out, graph, dep, &format!("{}{}", prefix, indent), seen)?;
+            }
+            None => writeln!(out, "{}{}{}", prefix, branch, name)?,
+        }
+    }
+    Ok(())
+}
+
+/// A light color, as a Graphviz HSV triple, picked by hashing `path`. Uses
+/// FNV-1a so colors stay the same between builds.
+fn file_color(path: &Path) -> String {
+    let hash = path
+        .as_os_str()
+        .as_encoded_bytes()
+        .iter()
+        .fold(0xcbf29ce484222325u64, |hash, b| {
+            (hash ^ *b as u64).wrapping_mul(0x100000001b3)
+        });
+    format!("{:.3} 0.300 1.000", (hash % 1000) as f64 / 1000.0)
+}
+
+/// Attribute list of a node or edge, nothing when there are no attributes.
+/// Joins attributes, each possibly a comma separated list itself, into a DOT
+/// attribute list. Their styles are combined, e.g. into
+/// `style="filled,dashed"`, as Graphviz only keeps the last `style`.
+fn dot_attrs(attrs: impl IntoIterator<Item = String>) -> String {
+    let mut list = Vec::new();
+    let mut styles = Vec::new();
+    let mut style_at = None;
+    for attrs in attrs {
+        for attr in split_attrs(&attrs) {
+            match attr.split_once('=') {
+                Some((key, style)) if key.trim() == "style" => {
+                    style_at.get_or_insert(list.len());
+                    let style = style.trim().trim_matches('"');
+                    styles.extend(style.split(',').map(|s| s.trim().to_string()));
+                }
+                _ => list.push(attr.to_string()),
+            }
+        }
+    }
+    if let Some(i) = style_at {
+        let style = match &styles[..] {
+            [style] => format!("style={}", style),
+            _ => format!("style=\"{}\"", styles.join(",")),
+        };
+        list.insert(i, style);
+    }
+    match list.is_empty() {
+        true => String::new(),
+        false => format!("[{}]", list.join(", ")),
+    }
+}
+
+/// Splits DOT attributes on the commas between them, not those in quoted
+/// values.
+fn split_attrs(attrs: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let (mut start, mut quoted, mut escaped) = (0, false, false);
+    for (i, c) in attrs.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                parts.push(attrs[start..i].trim());
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    parts.push(attrs[start..].trim());
+    parts.retain(|p| !p.is_empty());
+    parts
+}
+
+/// Escapes a string for a quoted DOT label. Node ids are always generated,
+/// so names only ever end up in labels, where `$` needs no escaping.
+fn dot_str(str: &str) -> String {
+    str.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Quotes a CSV field when it holds a separator, quote or line break.
+fn csv_str(str: &str) -> String {
+    match str.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", str.replace('"', "\"\"")),
+        false => str.to_string(),
+    }
+}
+
+/// PlantUML has no escape for quotes inside quoted names.
+fn plantuml_str(str: &str) -> String {
+    str.replace('"', "'")
+}
+
+/// Edges from tasks invoking `make` to the tasks they run in other makefiles.
+fn external_edges(graph: &DependencyGraph) -> Vec<(&str, &str)> {
+    let mut edges = Vec::new();
+    let mut externals = graph.externals.iter().collect::<Vec<_>>();
+    externals.sort();
+    for external in externals {
+        let m = match graph.makefiles.iter().find(|m| m.file == external.path) {
+            Some(v) => v,
+            None => {
+                eprintln!("External makefile not found: {:?}", external.path);
+                continue;
+            }
+        };
+
+        for task in external.tasks.iter() {
+            match m.get_id(task) {
+                Some(task_id) => edges.push((external.id.as_str(), task_id.as_str())),
+                None => eprintln!("External task not found: {}", task),
+            }
+        }
+    }
+    edges
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::HashMap,
+        path::{Path, PathBuf},
+    };
+
+    use serde_json::json;
+
+    use super::Options;
+    use crate::{
+        makefile::{Makefile, Walker},
+        testing::fixture,
+    };
+
+    #[test]
+    fn test_label_skip() {
+        let cases = [
+            (0, "/home/user/proj/Makefile", "/home/user/proj/Makefile"),
+            (2, "/home/user/proj/Makefile", "proj/Makefile"),
+            (2, "home/user/proj/Makefile", "proj/Makefile"),
+            (9, "/home/user/proj/Makefile", "Makefile"),
+        ];
+
+        for (i, (label_skip, path, expected)) in cases.into_iter().enumerate() {
+            let options = Options {
+                label_skip,
+                ..Default::default()
+            };
+            let label = options.label(Path::new(path));
+            assert_eq!(label, expected, "case {:02}, path: {:?}", i, path);
+        }
+    }
+
+    #[test]
+    fn test_plantuml() {
+        let root = fixture(
+            "plantuml",
+            &[
+                (
+                    "Makefile",
+                    "all: build\n\tmake -f sub/Makefile lib\nbuild:\n",
+                ),
+                ("sub/Makefile", "lib:\n"),
+            ],
+        );
+        let graph = Makefile::walk_from(root.join("Makefile")).unwrap();
+        let mut out = Vec::new();
+        super::write_plantuml(&mut out, &graph, &Default::default()).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert!(out.starts_with("@startuml\n"), "{}", out);
+        assert!(out.ends_with("@enduml\n"), "{}", out);
+        assert!(out.contains("\tcomponent \"all\" as task0\n"), "{}", out);
+        assert!(out.contains("\tcomponent \"build\" as task1\n"), "{}", out);
+        assert!(out.contains("\tcomponent \"lib\" as task2\n"), "{}", out);
+        assert!(out.contains("task0 --> task1\n"), "{}", out);
+        assert!(out.contains("task0 ..> task2\n"), "{}", out);
+    }
+
+    #[test]
+    fn test_annotate_source() {
+        let root = fixture(
+            "annotate_source",
+            &[
+                (
+                    "Makefile",
+                    "# comment\n\nall: lib\n\tmake -f sub/Makefile sub\nlib:\n",
+                ),
+                ("sub/Makefile", "\nsub:\n"),
+            ],
+        );
+        let graph = Makefile::walk_from(root.join("Makefile")).unwrap();
+        let options = Options {
+            label_skip: usize::MAX,
+            annotate_source: true,
+            ..Default::default()
+        };
+        let mut out = Vec::new();
+        super::write_dot(&mut out, &graph, &options).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        let expected = [
+            "\t\t// from Makefile:3\n\t\ttask0[label=\"all\"]\n",
+            "\t\t// from Makefile:3\n\t\ttask0 -> task1\n",
+            "\t\t// from Makefile:5\n\t\ttask1[label=\"lib\"]\n",
+            "\t\t// from Makefile:2\n\t\ttask2[label=\"sub\"]\n",
+            "\t// from Makefile:3\n\ttask0 -> task2\n",
+        ];
+        for expected in expected {
+            assert!(
+                out.contains(expected),
+                "missing {:?} in:\n{}",
+                expected,
+                out
+            );
+        }
+    }
+
+    #[test]
+    fn test_tree() {
+        let root = fixture(
+            "tree",
+            &[(
+                "Makefile",
+                "all: build test\nbuild: lib\ntest: lib\nlib: lib.c\n",
+            )],
+        );
+        let graph = Makefile::walk_from(root.join("Makefile")).unwrap();
+        let mut out = Vec::new();
+        super::write_tree(&mut out, &graph, "all").unwrap();
+
+        let expected = [
+            "all",
+            "├── build",
+            "│   └── lib",
+            "│       └── lib.c",
+            "└── test",
+            "    └── lib (*)",
+        ];
+        assert_eq!(String::from_utf8(out).unwrap(), expected.join("\n") + "\n");
+    }
+
+    #[test]
+    fn test_directories() {
+        let root = fixture(
+            "directories",
+            &[
+                (
+                    "Makefile",
+                    "all:\n\tmake -f sub/Makefile a\n\tmake -f sub/other.mk b\n",
+                ),
+                ("sub/Makefile", "a:\n\tmake -f other.mk b\n"),
+                ("sub/other.mk", "b:\n"),
+            ],
+        );
+        let graph = Makefile::walk_from(root.join("Makefile")).unwrap();
+        let options = Options {
+            label_skip: usize::MAX,
+            ..Default::default()
+        };
+        let mut out = Vec::new();
+        super::write_directories(&mut out, &graph, &options).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        let root = root.canonicalize().unwrap();
+        let expected = [
+            "digraph G {".to_string(),
+            format!(
+                "\tdir0[label=\"{}\"]",
+                root.file_name().unwrap().to_str().unwrap()
+            ),
+            "\tdir1[label=\"sub\"]".to_string(),
+            "\tdir0 -> dir1[label=\"2\"]".to_string(),
+            "}".to_string(),
+        ];
+        assert_eq!(out, expected.join("\n") + "\n");
+    }
+
+    #[test]
+    fn test_align_leaves() {
+        let root = fixture(
+            "align_leaves",
+            &[
+                (
+                    "Makefile",
+                    "all: build test\nbuild: lib.c\ntest:\n\tmake -f sub/Makefile check\n",
+                ),
+                ("sub/Makefile", "check:\n"),
+            ],
+        );
+        let graph = Makefile::walk_from(root.join("Makefile")).unwrap();
+        let options = Options {
+            align_leaves: true,
+            ..Default::default()
+        };
+        let mut out = Vec::new();
+        super::write_dot(&mut out, &graph, &options).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert!(
+            out.ends_with("\t{ rank=same; task1; task3; }\n}\n"),
+            "{}",
+            out
+        );
+    }
+
+    #[test]
+    fn test_html() {
+        let root = fixture(
+            "html",
+            &[
+                (
+                    "Makefile",
+                    "all: build\n\tmake -f sub/Makefile lib\nbuild:\n",
+                ),
+                ("sub/Makefile", "lib:\n"),
+            ],
+        );
+        let graph = Makefile::walk_from(root.join("Makefile")).unwrap();
+        let options = Options {
+            label_skip: usize::MAX,
+            ..Default::default()
+        };
+        let mut out = Vec::new();
+        super::write_html(&mut out, &graph, &options).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert!(out.starts_with("<!DOCTYPE html>\n"), "{}", out);
+        assert!(out.ends_with("</html>\n"), "{}", out);
+        assert!(
+            out.contains("<svg") || out.contains("<pre class=\"dot\">digraph G {"),
+            "{}",
+            out
+        );
+        let sections = [
+            "<summary>Makefile</summary>\n<ul>\n<li>all</li>\n<li>build</li>\n</ul>",
+            "<summary>Makefile</summary>\n<ul>\n<li>lib</li>\n</ul>",
+        ];
+        for section in sections {
+            assert!(out.contains(section), "missing {:?} in:\n{}", section, out);
+        }
+    }
+
+    #[test]
+    fn test_ranksep() {
+        let ranksep = |name: &str, tasks: usize, options: &Options| {
+            let data = (0..tasks).map(|i| format!("t{}:\n", i)).collect::<String>();
+            let root = fixture(name, &[("Makefile", &data)]);
+            let graph = Makefile::walk_from(root.join("Makefile")).unwrap();
+            let mut out = Vec::new();
+            super::write_dot(&mut out, &graph, options).unwrap();
+            let out = String::from_utf8(out).unwrap();
+            let line = out.lines().nth(1).unwrap().to_string();
+            line.strip_prefix("\tranksep=")
+                .unwrap()
+                .parse::<f64>()
+                .unwrap()
+        };
+
+        let small = ranksep("ranksep_small", 3, &Default::default());
+        let large = ranksep("ranksep_large", 300, &Default::default());
+        assert!(small < large, "{} >= {}", small, large);
+        assert_eq!(large, 3.0);
+
+        let options = Options {
+            ranksep: Some(1.5),
+            ..Default::default()
+        };
+        assert_eq!(ranksep("ranksep_fixed", 3, &options), 1.5);
+    }
+
+    #[test]
+    fn test_order_only() {
+        let root = fixture(
+            "order_only",
+            &[(
+                "Makefile",
+                "out: dir | dir\n\ttouch dir/out\nall: out | logs\ndir:\nlogs:\n",
+            )],
+        );
+        let graph = Makefile::walk_from(root.join("Makefile")).unwrap();
+        let m = &graph.makefiles[0];
+        let id = |name| m.get_id(name).unwrap();
+        assert_eq!(m.tasks[id("all")].order_only, vec!["logs"]);
+        let mut out = Vec::new();
+        super::write_dot(&mut out, &graph, &Default::default()).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        let (out_id, dir) = (id("out"), id("dir"));
+        assert!(
+            out.contains(&format!("\t\t{} -> {}\n", out_id, dir)),
+            "{}",
+            out
+        );
+        assert!(
+            out.contains(&format!("\t\t{} -> {}[style=dashed]\n", out_id, dir)),
+            "{}",
+            out
+        );
+        let edge = format!("\t\t{} -> {}[style=dashed]\n", id("all"), id("logs"));
+        assert!(out.contains(&edge), "{}", out);
+    }
+
+    #[test]
+    fn test_group_origins() {
+        let root = fixture(
+            "group_origins",
+            &[
+                ("Makefile", "include rules.mk\nall: lib\n"),
+                ("rules.mk", "lib: dir\ndir:\n"),
+            ],
+        );
+        let graph = Walker::new(root.join("Makefile"))
+            .merge_includes(true)
+            .walk()
+            .unwrap();
+        let options = Options {
+            label_skip: usize::MAX,
+            group_origins: true,
+            ..Default::default()
+        };
+        let mut out = Vec::new();
+        super::write_dot(&mut out, &graph, &options).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        // rules.mk is read in place of the include, before `all`.
+        assert!(out.contains("\t\ttask2[label=\"all\"]\n"), "{}", out);
+        assert!(out.contains("\t\ttask2 -> task0\n"), "{}", out);
+        assert!(
+            out.contains(
+                "\t\tsubgraph cluster_1 { style=dashed; label=\"rules.mk\"; task0; task1; }\n"
+            ),
+            "{}",
+            out
+        );
+
+        let options = Options {
+            group_origins: false,
+            ..options
+        };
+        let mut out = Vec::new();
+        super::write_dot(&mut out, &graph, &options).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(!out.contains("style=dashed;"), "{}", out);
+    }
+
+    #[test]
+    fn test_matrix() {
+        let root = fixture(
+            "matrix",
+            &[
+                (
+                    "Makefile",
+                    "all: lib | dir\n\tmake -f sub/Makefile sub\nlib: dir\ndir:\n",
+                ),
+                ("sub/Makefile", "sub:\n"),
+            ],
+        );
+        let graph = Makefile::walk_from(root.join("Makefile")).unwrap();
+        let mut out = Vec::new();
+        super::write_matrix(&mut out, &graph).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert_eq!(
+            out,
+            ",all,lib,dir,sub\n\
+             all,0,1,2,4\n\
+             lib,0,0,1,0\n\
+             dir,0,0,0,0\n\
+             sub,0,0,0,0\n"
+        );
+        assert_eq!(super::csv_str("a,\"b\""), "\"a,\"\"b\"\"\"");
+    }
+
+    #[test]
+    fn test_json() {
+        let root = fixture(
+            "json",
+            &[
+                (
+                    "Makefile",
+                    "all: lib main.c\n\tmake -f sub/Makefile sub\nlib:\n.PHONY: all\n",
+                ),
+                ("sub/Makefile", "sub:\n\techo \"done\"\n"),
+            ],
+        );
+        let graph = Makefile::walk_from(root.join("Makefile")).unwrap();
+        let mut out = Vec::new();
+        super::write_json(&mut out, &graph).unwrap();
+        let out: serde_json::Value = serde_json::from_slice(&out).unwrap();
+
+        let cases = [
+            (
+                "/makefiles/0/tasks/0",
+                json!({
+                    "id": "task0",
+                    "name": "all",
+                    "phony": true,
+                    "dependencies": ["lib", "main.c"],
+                    "order_only": [],
+                    "commands": ["make -f sub/Makefile sub"],
+                }),
+            ),
+            (
+                "/makefiles/0/tasks/1",
+                json!({
+                    "id": "task1",
+                    "name": "lib",
+                    "phony": false,
+                    "dependencies": [],
+                    "order_only": [],
+                    "commands": [],
+                }),
+            ),
+            (
+                "/makefiles/1/tasks/0",
+                json!({
+                    "id": "task3",
+                    "name": "sub",
+                    "phony": false,
+                    "dependencies": [],
+                    "order_only": [],
+                    "commands": ["echo \"done\""],
+                }),
+            ),
+            (
+                "/makefiles/1/file",
+                json!(root.join("sub/Makefile").display().to_string()),
+            ),
+            (
+                "/externals",
+                json!([{
+                    "path": root.join("sub/Makefile").display().to_string(),
+                    "id": "task0",
+                    "tasks": ["sub"],
+                }]),
+            ),
+        ];
+        for (i, (pointer, expected)) in cases.iter().enumerate() {
+            assert_eq!(out.pointer(pointer), Some(expected), "case {:02}", i);
+        }
+    }
+
+    #[test]
+    fn test_changes() {
+        let old = fixture("changes_old", &[("Makefile", "all: lib\nlib:\ndoc:\n")]);
+        let new = fixture(
+            "changes_new",
+            &[("Makefile", "all: lib\nlib: test\ntest:\ndoc:\n")],
+        );
+        let mut before = Makefile::walk_from(old.join("Makefile")).unwrap();
+        let after = Makefile::walk_from(new.join("Makefile")).unwrap();
+        before.relocate(&old.canonicalize().unwrap(), &new.canonicalize().unwrap());
+        let options = Options {
+            changes: Some(before.diff(&after)),
+            ..Default::default()
+        };
+        let mut out = Vec::new();
+        super::write_dot(&mut out, &after, &options).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        let m = &after.makefiles[0];
+        let id = |name| m.get_id(name).unwrap();
+        let changed = "color=red, penwidth=2]\n";
+        let dimmed = "color=gray70, fontcolor=gray70]\n";
+        for (name, style) in [
+            ("test", changed),
+            ("lib", changed),
+            ("all", dimmed),
+            ("doc", dimmed),
+        ] {
+            let node = format!("\t\t{}[label=\"{}\", {}", id(name), name, style);
+            assert!(out.contains(&node), "{}: {}", name, out);
+        }
+        let edge = format!("\t\t{} -> {}[{}", id("lib"), id("test"), changed);
+        assert!(out.contains(&edge), "{}", out);
+        let edge = format!("\t\t{} -> {}[{}", id("all"), id("lib"), dimmed);
+        assert!(out.contains(&edge), "{}", out);
+    }
+
+    #[test]
+    fn test_stable_output() {
+        let root = fixture(
+            "stable_output",
+            &[
+                (
+                    "Makefile",
+                    "all: z y x\n\tmake -C a\n\tmake -C b one two\n\tmake -C c\n\tmake -C $$DIR\nz: w\ny:\nx:\nw:\n",
+                ),
+                ("a/Makefile", "a1: a2 a3\na2:\na3:\n"),
+                ("b/Makefile", "one:\ntwo:\n\tmake -C ../c\n"),
+                ("c/Makefile", "c3:\nc2:\nc1:\n"),
+            ],
+        );
+        let write = || {
+            let graph = Makefile::walk_from(root.join("Makefile")).unwrap();
+            let mut out = Vec::new();
+            super::write_dot(&mut out, &graph, &Default::default()).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        let first = write();
+        for _ in 0..10 {
+            assert_eq!(write(), first);
+        }
+        let order = ["\"z\"", "\"y\"", "\"x\"", "\"w\""].map(|name| first.find(name).unwrap());
+        assert!(order.windows(2).all(|w| w[0] < w[1]), "{}", first);
+    }
+
+    #[test]
+    fn test_phony_style() {
+        let root = fixture(
+            "phony_style",
+            &[("Makefile", ".PHONY: all\nall: out\nout:\n\ttouch out\n")],
+        );
+        let graph = Makefile::walk_from(root.join("Makefile")).unwrap();
+        let m = &graph.makefiles[0];
+        let (all, out) = (m.get_id("all").unwrap(), m.get_id("out").unwrap());
+        let cases = [
+            (None, "[label=\"all\", shape=box, style=dashed]"),
+            (Some(""), "[label=\"all\"]"),
+            (Some("shape=oval"), "[label=\"all\", shape=oval]"),
+        ];
+
+        for (i, (style, expected)) in cases.into_iter().enumerate() {
+            let options = Options {
+                phony_style: style.map(String::from),
+                ..Default::default()
+            };
+            let mut dot = Vec::new();
+            super::write_dot(&mut dot, &graph, &options).unwrap();
+            let dot = String::from_utf8(dot).unwrap();
+            let node = format!("\t\t{}{}\n", all, expected);
+            assert!(dot.contains(&node), "case {:02}: {}", i, dot);
+            let node = format!("\t\t{}[label=\"out\"]\n", out);
+            assert!(dot.contains(&node), "case {:02}: {}", i, dot);
+        }
+    }
+
+    #[test]
+    fn test_fonts() {
+        let root = fixture("fonts", &[("Makefile", "all: lib\nlib:\n")]);
+        let graph = Makefile::walk_from(root.join("Makefile")).unwrap();
+        let write = |options: &Options| {
+            let mut out = Vec::new();
+            super::write_dot(&mut out, &graph, options).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert!(!write(&Default::default()).contains("fontname"));
+        let options = Options {
+            font_name: Some("Helvetica".to_string()),
+            font_size: Some(10.5),
+            ..Default::default()
+        };
+        let out = write(&options);
+        for kind in ["graph", "node", "edge"] {
+            let line = format!("\n\t{} [fontname=\"Helvetica\", fontsize=10.5]\n", kind);
+            assert!(out.contains(&line), "{}", out);
+        }
+        assert!(
+            out.find("edge [").unwrap() < out.find("subgraph").unwrap(),
+            "{}",
+            out
+        );
+    }
+
+    #[test]
+    fn test_compact() {
+        let root = fixture("compact", &[("Makefile", "a: b\nb: c\nc: d\nd: e\ne:\n")]);
+        let mut graph = Makefile::walk_from(root.join("Makefile")).unwrap();
+        graph.compact();
+        let options = Options {
+            compact: true,
+            ..Default::default()
+        };
+        let mut out = Vec::new();
+        super::write_dot(&mut out, &graph, &options).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert!(
+            out.contains("\t\ttask0 -> task4[label=\"+3\"]\n"),
+            "{}",
+            out
+        );
+        assert_eq!(out.matches("[label=\"").count(), 3, "{}", out);
+
+        // A long chain folds into a single edge without rescanning it for
+        // every task.
+        let data = (0..3000)
+            .map(|i| format!("t{}: t{}\n", i, i + 1))
+            .collect::<String>()
+            + "t3000:\n";
+        let files = HashMap::from([(PathBuf::from("/p/Makefile"), data)]);
+        let mut graph = Walker::new("/p/Makefile").files(files).walk().unwrap();
+        graph.compact();
+        let mut out = Vec::new();
+        super::write_dot(&mut out, &graph, &options).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(
+            out.contains("\t\ttask0 -> task3000[label=\"+2999\"]\n"),
+            "{}",
+            out
+        );
+    }
+
+    #[test]
+    fn test_truncated() {
+        let root = fixture("truncated", &[("Makefile", "all: aa bb\naa:\nbb:\n")]);
+        let mut graph = Makefile::walk_from(root.join("Makefile")).unwrap();
+        let options = Options {
+            truncated: graph.truncate(2),
+            ..Default::default()
+        };
+        let mut out = Vec::new();
+        super::write_dot(&mut out, &graph, &options).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert_eq!(out.matches("[label=").count(), 2, "{}", out);
+        assert!(
+            out.contains("\tlabel=\"1 more tasks not shown\"\n"),
+            "{}",
+            out
+        );
+    }
+
+    #[test]
+    fn test_fragment() {
+        let root = fixture(
+            "fragment",
+            &[
+                ("Makefile", "all: lib\n\tmake -f sub/Makefile sub\nlib:\n"),
+                ("sub/Makefile", "sub:\n"),
+            ],
+        );
+        let graph = Makefile::walk_from(root.join("Makefile")).unwrap();
+        let options = Options {
+            fragment: true,
+            ..Default::default()
+        };
+        let mut out = Vec::new();
+        super::write_dot(&mut out, &graph, &options).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert!(!out.contains("digraph"), "{}", out);
+        assert!(!out.contains("ranksep"), "{}", out);
+        assert!(out.starts_with("\tsubgraph cluster_0 {\n"), "{}", out);
+        assert!(out.contains("\tsubgraph cluster_1 {\n"), "{}", out);
+        assert!(out.ends_with("\ttask0 -> task2\n"), "{}", out);
+    }
+
+    #[test]
+    fn test_dollar_names() {
+        let root = fixture(
+            "dollar_names",
+            &[("Makefile", "NAME = gen\nall: $(NAME)$$x\n$(NAME)$$x:\n")],
+        );
+        let graph = Makefile::walk_from(root.join("Makefile")).unwrap();
+        let makefile = &graph.makefiles[0];
+        let id = makefile.get_id("gen$x").expect("gen$x not found");
+        assert!(makefile.tasks.values().any(|t| t.dependencies == ["gen$x"]));
+        let mut out = Vec::new();
+        super::write_dot(&mut out, &graph, &Default::default()).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert!(
+            out.contains(&format!("\t\t{}[label=\"gen$x\"]\n", id)),
+            "{}",
+            out
+        );
+        assert_eq!(super::dot_str(r#"a\b "$x""#), r#"a\\b \"$x\""#);
+    }
+
+    #[test]
+    fn test_color_by_makefile() {
+        let root = fixture(
+            "color_by_makefile",
+            &[
+                (
+                    "Makefile",
+                    "all: lib\n\tmake -f sub/Makefile sub\nlib:\n.PHONY: all\n",
+                ),
+                ("sub/Makefile", "sub:\n"),
+            ],
+        );
+        let graph = Makefile::walk_from(root.join("Makefile")).unwrap();
+        let options = Options {
+            color_by_makefile: true,
+            ..Default::default()
+        };
+        let mut out = Vec::new();
+        super::write_dot(&mut out, &graph, &options).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        let color = |id: &str| {
+            let prefix = format!("\t\t{}[label=", id);
+            let line = out.lines().find(|l| l.starts_with(&prefix)).unwrap();
+            let (_, color) = line.split_once("fillcolor=").unwrap();
+            color.to_string()
+        };
+        assert_eq!(color("task1"), color("task2"));
+        assert_ne!(color("task1"), color("task3"));
+
+        // Filled and dashed, rather than one style replacing the other.
+        let all = graph.find_task("all").unwrap();
+        let line = format!(
+            "\t\t{}[label=\"all\", style=\"filled,dashed\", fillcolor=",
+            all
+        );
+        let line = out.lines().find(|l| l.starts_with(&line)).expect(&out);
+        assert_eq!(line.matches("style=").count(), 1, "{}", line);
+        assert!(line.ends_with(", shape=box]"), "{}", line);
+    }
+}
@@ -0,0 +1,14 @@
+use std::path::PathBuf;
+
+/// Writes `files` into a fresh directory under the system temp dir and
+/// returns its path.
+pub fn fixture(name: &str, files: &[(&str, &str)]) -> PathBuf {
+    let root = std::env::temp_dir().join(format!("makedot-{}", name));
+    let _ = std::fs::remove_dir_all(&root);
+    for (path, content) in files {
+        let path = root.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, content).unwrap();
+    }
+    root
+}